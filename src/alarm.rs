@@ -1 +1,528 @@
+pub mod alarm_engine {
+    use crate::data::data_forms::{Alarm, ConfigMain, Contact, TransitionCause, User, UserState};
+    use crate::data_handler::sqlite_handler::DatabaseState;
+    use crate::mail_handler::smtp_handler::{self, MailError, Mailer};
+    use crate::signature_handler::signing::ServerKey;
+    use crate::state_engine::state_functions;
 
+    use rand::{distributions::Alphanumeric, Rng};
+    use std::{collections::HashMap, fmt};
+
+    /// The stages an alarm is escalated through
+    /// Warned: The overdue user was asked to check in
+    /// ContactsNotified: The grace period passed, the user is marked as deceased and notified about
+    /// AdminNotified: Nobody acknowledged the alarm, the admin is notified
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum AlarmStage {
+        Warned,
+        ContactsNotified,
+        AdminNotified,
+    }
+
+    /// Returns the stage the given alarm has to be escalated to at the given time,
+    /// None if nothing has to be done right now. The link only acknowledges alarms whose
+    /// contacts were notified, see `Alarm`, so it only ever stops the admin stage.
+    pub fn next_stage(alarm: &Alarm, now: u32, admin_grace_period: u32) -> Option<AlarmStage> {
+        if alarm.acknowledged.is_some() {
+            return None;
+        }
+        match alarm.contacts_notified {
+            None if now >= alarm.grace_until => Some(AlarmStage::ContactsNotified),
+            None if alarm.warned.is_none() => Some(AlarmStage::Warned),
+            Some(time) if alarm.admin_notified.is_none() && now >= time + admin_grace_period => {
+                Some(AlarmStage::AdminNotified)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the time the given alarm has to be escalated next, None if it won't be escalated
+    /// any further. Warnings are not considered, they are sent on the tick right after the alarm
+    /// was raised, see `AlarmEngine::raise`.
+    pub fn next_due(alarm: &Alarm, admin_grace_period: u32) -> Option<u32> {
+        if alarm.acknowledged.is_some() {
+            return None;
+        }
+        match alarm.contacts_notified {
+            None => Some(alarm.grace_until),
+            Some(time) if alarm.admin_notified.is_none() => Some(time + admin_grace_period),
+            _ => None,
+        }
+    }
+
+    /// Everything that can go wrong while escalating an alarm, the stage is retried on the next tick
+    #[derive(Debug)]
+    pub enum EscalationError {
+        Mail(MailError),
+        Database(rusqlite::Error),
+    }
+    impl fmt::Display for EscalationError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                EscalationError::Mail(e) => write!(f, "{}", e),
+                EscalationError::Database(e) => write!(f, "Database error: {}", e),
+            }
+        }
+    }
+    impl std::error::Error for EscalationError {}
+    impl From<MailError> for EscalationError {
+        fn from(e: MailError) -> Self {
+            EscalationError::Mail(e)
+        }
+    }
+    impl From<std::io::Error> for EscalationError {
+        fn from(e: std::io::Error) -> Self {
+            EscalationError::Mail(e.into())
+        }
+    }
+    impl From<rusqlite::Error> for EscalationError {
+        fn from(e: rusqlite::Error) -> Self {
+            EscalationError::Database(e)
+        }
+    }
+
+    /// The escalation engine, raises alarms for overdue users and escalates them on every tick
+    pub struct AlarmEngine {
+        users: DatabaseState,
+        alarms: DatabaseState,
+        contacts: DatabaseState,
+        mailer: Mailer,
+        key: ServerKey,
+        cfg: ConfigMain,
+    }
+    impl AlarmEngine {
+        /// Initialize a new alarm engine
+        pub fn init(
+            db_path: String,
+            mailer: Mailer,
+            key: ServerKey,
+            cfg: ConfigMain,
+        ) -> Result<Self, rusqlite::Error> {
+            let users = DatabaseState::init(db_path.clone())?;
+            let alarms =
+                DatabaseState::init_with_table_name(db_path.clone(), "alarms".to_string())?;
+            let contacts = DatabaseState::init_with_table_name(db_path, "contacts".to_string())?;
+            Ok(Self {
+                users,
+                alarms,
+                contacts,
+                mailer,
+                key,
+                cfg,
+            })
+        }
+        /// Raise an alarm for the given user, who just missed their deadline.
+        /// The user is marked as overdue and will be warned on the next tick.
+        pub fn raise(&self, user: &User, now: u32) -> Result<(), rusqlite::Error> {
+            if self
+                .users
+                .update_state_user(&user.id, UserState::Overdue, TransitionCause::Timeout)?
+                .is_err()
+            {
+                // The user is already deceased, an alarm is already running
+                return Ok(());
+            }
+            self.alarms.set_alarm(&Alarm {
+                id: user.id.clone(),
+                ack_code: rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(32)
+                    .map(char::from)
+                    .collect(),
+                raised: now,
+                grace_until: now + self.cfg.alarm_config.warn_grace_period,
+                warned: None,
+                contacts_notified: None,
+                admin_notified: None,
+                acknowledged: None,
+            })?;
+            self.log(user, "User missed the deadline, an alarm was raised");
+            Ok(())
+        }
+        /// Escalate all active alarms which are due and raise alarms (without warning) for
+        /// users which were marked as deceased otherwise. Stages whose email could not be
+        /// sent are retried on the next tick.
+        /// Returns the time the next stage of the remaining alarms is due, None if there is none.
+        pub fn tick(&self, now: u32) -> Result<Option<u32>, rusqlite::Error> {
+            let admin_grace_period = self.cfg.alarm_config.admin_grace_period;
+            let mut next = None;
+            let mut active = self.alarms.get_active_alarms()?;
+            // Users marked as deceased without an alarm (e.g. via sign) skip the warning.
+            // An alarm which was acknowledged or fully escalated stays in place, it must
+            // not be replaced by a new one notifying everybody once more.
+            for user in self.users.get_user_by_state(UserState::Deceased)? {
+                if self.alarms.get_alarm_by_id(&user.id)?.is_some() {
+                    continue;
+                }
+                let alarm = Alarm {
+                    id: user.id.clone(),
+                    ack_code: rand::thread_rng()
+                        .sample_iter(&Alphanumeric)
+                        .take(32)
+                        .map(char::from)
+                        .collect(),
+                    raised: now,
+                    grace_until: now,
+                    warned: None,
+                    contacts_notified: None,
+                    admin_notified: None,
+                    acknowledged: None,
+                };
+                self.alarms.set_alarm(&alarm)?;
+                self.log(&user, "User was marked as deceased, an alarm was raised");
+                active.push(alarm);
+            }
+
+            for mut alarm in active {
+                let user = match self.users.get_user_by_id(&alarm.id)? {
+                    Some(user) => user,
+                    None => {
+                        // The account was deleted in the meantime
+                        self.alarms.delete_user(&alarm.id)?;
+                        continue;
+                    }
+                };
+                // The user checked in or paused monitoring before the contacts were notified
+                if alarm.contacts_notified.is_none()
+                    && !matches!(user.state, UserState::Overdue | UserState::Deceased)
+                {
+                    alarm.acknowledged = Some(now);
+                    self.alarms.set_alarm(&alarm)?;
+                    self.log(&user, "User checked in, the alarm was acknowledged");
+                    continue;
+                }
+                if let Some(stage) = next_stage(&alarm, now, admin_grace_period) {
+                    if let Err(e) = self.escalate(&user, &mut alarm, stage, now) {
+                        log::error!(
+                            "Failed to escalate alarm of user {} to {:?}!\n: {}",
+                            user.id,
+                            stage,
+                            e
+                        );
+                        continue;
+                    }
+                    self.alarms.set_alarm(&alarm)?;
+                }
+                if let Some(due) = next_due(&alarm, admin_grace_period) {
+                    next = Some(next.map_or(due, |next: u32| next.min(due)));
+                }
+            }
+            Ok(next)
+        }
+        /// Escalate the given alarm to the given stage, sends the associated email and
+        /// sets the timestamp of the stage
+        fn escalate(
+            &self,
+            user: &User,
+            alarm: &mut Alarm,
+            stage: AlarmStage,
+            now: u32,
+        ) -> Result<(), EscalationError> {
+            let mut values = HashMap::new();
+            values.insert("email", user.email.clone());
+            values.insert("time", smtp_handler::format_timestamp(now));
+            values.insert("raised", smtp_handler::format_timestamp(alarm.raised));
+            values.insert(
+                "grace_until",
+                smtp_handler::format_timestamp(alarm.grace_until),
+            );
+            values.insert(
+                "ack_link",
+                format!(
+                    "{}/api/alarm/acknowledge/{}",
+                    self.cfg.server_config.public_url.trim_end_matches('/'),
+                    alarm.ack_code
+                ),
+            );
+            let files = &self.cfg.file_locations;
+
+            match stage {
+                AlarmStage::Warned => {
+                    let body =
+                        smtp_handler::render_template_file(&files.warning_body_scheme, &values)?;
+                    self.mailer.send(
+                        &user.email,
+                        false,
+                        "Dead Man Notification Beacon: Please check in",
+                        body,
+                    )?;
+                    alarm.warned = Some(now);
+                    self.log(user, "User was warned about the missed deadline");
+                }
+                AlarmStage::ContactsNotified => {
+                    // The grace period passed, from now on the user is deceased
+                    match self.users.update_state_user(
+                        &user.id,
+                        UserState::Deceased,
+                        TransitionCause::Escalation,
+                    )? {
+                        Ok(_) => (),
+                        // Users marked as deceased otherwise already are
+                        Err(e) if e.from == UserState::Deceased => (),
+                        Err(e) => {
+                            // The user checked in, paused or was revived in the meantime,
+                            // the alarm is acknowledged on the next tick
+                            log::debug!("USER {} alarm not escalated: {}", user.id, e);
+                            return Ok(());
+                        }
+                    }
+                    let template = std::fs::read_to_string(&files.email_body_scheme)?;
+                    // Only contacts which confirmed are notified
+                    let mut contacts: Vec<Contact> = self
+                        .contacts
+                        .get_contacts_by_user(&user.id)?
+                        .into_iter()
+                        .filter(|c| c.active)
+                        .collect();
+                    // Without any contacts the user's own address is the only one on file
+                    if contacts.is_empty() {
+                        contacts.push(Contact {
+                            id: 0,
+                            user_id: user.id.clone(),
+                            name: user.email.clone(),
+                            email: user.email.clone(),
+                            message: "".to_string(),
+                            active: true,
+                        });
+                    }
+                    // The stage is reached once any contact was notified,
+                    // failed contacts are only logged to not notify the others twice
+                    let mut last_error = None;
+                    let mut notified = 0;
+                    for contact in contacts.iter() {
+                        values.insert("name", contact.name.clone());
+                        values.insert("message", contact.message.clone());
+                        values.insert(
+                            "unsubscribe_link",
+                            if contact.id == 0 {
+                                "".to_string()
+                            } else {
+                                state_functions::unsubscribe_link(&self.cfg, &self.key, contact)
+                            },
+                        );
+                        let sent = self.mailer.send(
+                            &contact.email,
+                            false,
+                            "Dead Man Notification Beacon: Notification",
+                            smtp_handler::render_template(&template, &values),
+                        );
+                        match sent {
+                            Ok(_) => notified += 1,
+                            Err(e) => {
+                                log::error!("Failed to notify contact {}!\n: {}", contact.id, e);
+                                last_error = Some(e);
+                            }
+                        }
+                    }
+                    if notified == 0 {
+                        if let Some(e) = last_error {
+                            return Err(e.into());
+                        }
+                    }
+                    alarm.contacts_notified = Some(now);
+                    match self.users.update_state_user(
+                        &user.id,
+                        UserState::Notified,
+                        TransitionCause::Escalation,
+                    ) {
+                        Ok(Ok(_)) => (),
+                        Ok(Err(e)) => log::error!("{}", e),
+                        Err(e) => log::error!("{}", e),
+                    }
+                    self.log(
+                        user,
+                        &format!(
+                            "Notification about the deceased user was sent to {} of {} contacts",
+                            notified,
+                            contacts.len()
+                        ),
+                    );
+                }
+                AlarmStage::AdminNotified => {
+                    let body =
+                        smtp_handler::render_template_file(&files.admin_body_scheme, &values)?;
+                    if self
+                        .mailer
+                        .send_admin("Dead Man Notification Beacon: Unacknowledged alarm", body)?
+                    {
+                        self.log(
+                            user,
+                            "Nobody acknowledged the alarm, the admin was notified",
+                        );
+                    } else {
+                        self.log(
+                            user,
+                            "Nobody acknowledged the alarm, no admin is configured",
+                        );
+                    }
+                    alarm.admin_notified = Some(now);
+                }
+            }
+            log::debug!("USER {} alarm escalated to {:?}", user.id, stage);
+            Ok(())
+        }
+        /// Write the given message to the audit log of the user
+        fn log(&self, user: &User, message: &str) {
+            if let Err(e) = state_functions::custom_log_line(user, message.to_string(), &self.users)
+            {
+                log::error!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alarm::alarm_engine::{next_due, next_stage, AlarmEngine, AlarmStage};
+    use crate::data::data_forms::{
+        Alarm, ConfigMain, ConfigSmtp, SmtpSecurity, TransitionCause, UserState,
+    };
+    use crate::data_handler::sqlite_handler::{migrate_database, DatabaseState};
+    use crate::mail_handler::smtp_handler::Mailer;
+    use crate::signature_handler::signing::ServerKey;
+
+    use std::{io, net::TcpListener};
+
+    #[test]
+    fn escalation_stages() {
+        let mut alarm = Alarm {
+            id: "foo".to_string(),
+            ack_code: "bar".to_string(),
+            raised: 100,
+            grace_until: 200,
+            warned: None,
+            contacts_notified: None,
+            admin_notified: None,
+            acknowledged: None,
+        };
+        assert_eq!(next_stage(&alarm, 100, 50), Some(AlarmStage::Warned));
+        alarm.warned = Some(100);
+        assert_eq!(next_stage(&alarm, 150, 50), None);
+        assert_eq!(next_due(&alarm, 50), Some(200));
+        assert_eq!(
+            next_stage(&alarm, 200, 50),
+            Some(AlarmStage::ContactsNotified)
+        );
+        alarm.contacts_notified = Some(200);
+        assert_eq!(next_stage(&alarm, 249, 50), None);
+        assert_eq!(next_due(&alarm, 50), Some(250));
+        assert_eq!(next_stage(&alarm, 250, 50), Some(AlarmStage::AdminNotified));
+        alarm.admin_notified = Some(250);
+        assert_eq!(next_stage(&alarm, 1000, 50), None);
+        assert_eq!(next_due(&alarm, 50), None);
+
+        // Acknowledged alarms are not escalated any further
+        alarm.admin_notified = None;
+        alarm.acknowledged = Some(240);
+        assert_eq!(next_stage(&alarm, 1000, 50), None);
+
+        // Alarms without grace period skip the warning
+        alarm.warned = None;
+        alarm.contacts_notified = None;
+        alarm.acknowledged = None;
+        alarm.grace_until = 100;
+        assert_eq!(
+            next_stage(&alarm, 100, 50),
+            Some(AlarmStage::ContactsNotified)
+        );
+    }
+    /// Creates an engine on a new database at a temporary path. Every email would be sent to
+    /// the returned listener, which never accepts a connection.
+    fn test_engine(name: &str) -> (AlarmEngine, TcpListener, String) {
+        let path = std::env::temp_dir().join(format!("{}.sqlite", name));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        migrate_database(&path).unwrap();
+        let smtp = TcpListener::bind("127.0.0.1:0").unwrap();
+        smtp.set_nonblocking(true).unwrap();
+        let mailer = Mailer::from_config(&ConfigSmtp {
+            smtp_port: smtp.local_addr().unwrap().port(),
+            smtp_security: SmtpSecurity::None,
+            smtp_username: "".to_string(),
+            ..ConfigSmtp::default()
+        })
+        .unwrap();
+        let engine = AlarmEngine::init(
+            path.clone(),
+            mailer,
+            ServerKey::from_bytes([0; 32]),
+            ConfigMain::default(),
+        )
+        .unwrap();
+        (engine, smtp, path)
+    }
+
+    #[test]
+    fn acknowledged_alarm_stays() {
+        let (engine, smtp, path) = test_engine("dmnb_acknowledged_alarm");
+
+        log::debug!("Acknowledging the alarm of a user who stays deceased");
+        let db = DatabaseState::init(path.clone()).unwrap();
+        let (user, _) = db.new_user(&"foo@example.com".to_string()).unwrap();
+        db.update_state_user(&user.id, UserState::Deceased, TransitionCause::Sign)
+            .unwrap()
+            .unwrap();
+        let alarms =
+            DatabaseState::init_with_table_name(path.clone(), "alarms".to_string()).unwrap();
+        let alarm = Alarm {
+            id: user.id.clone(),
+            ack_code: "bar".to_string(),
+            raised: 100,
+            grace_until: 100,
+            warned: None,
+            contacts_notified: Some(100),
+            admin_notified: None,
+            acknowledged: Some(150),
+        };
+        alarms.set_alarm(&alarm).unwrap();
+
+        assert_eq!(engine.tick(200), Ok(None));
+        assert_eq!(alarms.get_alarm_by_id(&user.id), Ok(Some(alarm)));
+        assert_eq!(
+            smtp.accept().map_err(|e| e.kind()).err(),
+            Some(io::ErrorKind::WouldBlock)
+        );
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn contacts_not_found() {
+        let (engine, smtp, path) = test_engine("dmnb_contacts_not_found");
+
+        log::debug!("Failing to look up the contacts of a deceased user");
+        let db = DatabaseState::init(path.clone()).unwrap();
+        let (user, _) = db.new_user(&"foo@example.com".to_string()).unwrap();
+        db.update_state_user(&user.id, UserState::Deceased, TransitionCause::Sign)
+            .unwrap()
+            .unwrap();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute("DROP TABLE contacts", [])
+            .unwrap();
+
+        // Nobody is notified in place of the contacts, the stage is retried on the next tick
+        assert_eq!(engine.tick(200), Ok(None));
+        let alarms =
+            DatabaseState::init_with_table_name(path.clone(), "alarms".to_string()).unwrap();
+        assert_eq!(
+            alarms
+                .get_alarm_by_id(&user.id)
+                .unwrap()
+                .unwrap()
+                .contacts_notified,
+            None
+        );
+        assert_eq!(
+            db.get_user_by_id(&user.id).unwrap().unwrap().state,
+            UserState::Deceased
+        );
+        assert_eq!(
+            smtp.accept().map_err(|e| e.kind()).err(),
+            Some(io::ErrorKind::WouldBlock)
+        );
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,916 @@
+pub mod audit_log {
+    #![allow(non_snake_case)]
+
+    use crate::data::data_forms::{AuditVerification, ConfigAudit, Device, RequestPayload};
+    use crate::data_handler::sqlite_handler::DatabaseState;
+    use crate::error::server_error::ServerError;
+    use crate::signature_handler::signing::{self, ServerKey};
+
+    use chrono::{NaiveDateTime, Utc};
+    use serde_derive::{Deserialize, Serialize};
+    use std::{
+        collections::HashMap,
+        convert::{TryFrom, TryInto},
+        fs,
+        io::{prelude::*, BufReader},
+        path::{Path, PathBuf},
+        thread,
+        time::Duration,
+    };
+
+    /// The maximum number of entries returned by a query
+    pub const MAX_PAGE_SIZE: usize = 250;
+    /// The number of entries returned by a query which sets no limit
+    pub const DEFAULT_PAGE_SIZE: usize = 50;
+    /// How often the audit logs are compacted, purged and their heads are signed
+    pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(600);
+    /// The extension of logs in the format written before JSON Lines, see `parse_legacy_line`
+    const LEGACY_EXTENSION: &str = "log";
+    /// The extension of logs written as JSON Lines before they were kept in the database
+    const JSONL_EXTENSION: &str = "jsonl";
+
+    /// What caused an entry in the audit log
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum AuditKind {
+        /// Message type 1, a check-in which doesn't change the state of the user
+        Audit,
+        /// Message type 2, the user signed that they are deceased
+        Sign,
+        /// Message type 3, the user is alive
+        Heartbeat,
+        /// A message of the server, see `state_functions::custom_log_line`
+        System,
+        /// The server signed the hash chain up to this entry
+        Checkpoint,
+        /// A request imported from a legacy log, which didn't record the message type
+        Imported,
+        /// The server signed where the log starts after older entries were compacted away
+        Anchor,
+    }
+    impl AuditKind {
+        /// Returns the name used to store this kind in the database
+        pub fn as_str(self) -> &'static str {
+            match self {
+                AuditKind::Audit => "audit",
+                AuditKind::Sign => "sign",
+                AuditKind::Heartbeat => "heartbeat",
+                AuditKind::System => "system",
+                AuditKind::Checkpoint => "checkpoint",
+                AuditKind::Imported => "imported",
+                AuditKind::Anchor => "anchor",
+            }
+        }
+        /// Returns the kind stored as the given name in the database,
+        /// or None if the name is no valid kind
+        pub fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "audit" => Some(AuditKind::Audit),
+                "sign" => Some(AuditKind::Sign),
+                "heartbeat" => Some(AuditKind::Heartbeat),
+                "system" => Some(AuditKind::System),
+                "checkpoint" => Some(AuditKind::Checkpoint),
+                "imported" => Some(AuditKind::Imported),
+                "anchor" => Some(AuditKind::Anchor),
+                _ => None,
+            }
+        }
+    }
+
+    /// A single entry in the audit log of a user, stored as JSON in the audit table.
+    /// Every entry contains the hash of the entry before it, so entries can't be changed or
+    /// removed without breaking the chain, see `verify`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct AuditRecord {
+        /// The time the server wrote this entry
+        pub time: u32,
+        pub kind: AuditKind,
+        /// The time, previous time and next time of the request as sent by the client
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub T: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub Tp: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub Td: Option<u32>,
+        /// The location [Latitude, Longitude, other] sent by the client
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub L: Option<Vec<String>>,
+        /// The extras sent by the client
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub O: Option<HashMap<String, Vec<String>>>,
+        /// The device which made the request, if it wasn't the user itself
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub device_id: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub device_name: Option<String>,
+        /// The message of system entries
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub message: Option<String>,
+        /// The hash of the previous entry of the log, the first entry is chained to the user id.
+        /// Anchors name the hash of the newest entry which was compacted away.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub prev: Option<String>,
+        /// The signature of `prev` by the server, only set for checkpoints and anchors
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub signature: Option<String>,
+    }
+    impl AuditRecord {
+        /// Create an entry of the given kind without any content
+        fn empty(kind: AuditKind, time: u32) -> Self {
+            Self {
+                time,
+                kind,
+                T: None,
+                Tp: None,
+                Td: None,
+                L: None,
+                O: None,
+                device_id: None,
+                device_name: None,
+                message: None,
+                prev: None,
+                signature: None,
+            }
+        }
+        /// Create an entry for the given request, attributed to the device which made it
+        pub fn from_payload(
+            kind: AuditKind,
+            payload: &RequestPayload,
+            device: Option<&Device>,
+            time: u32,
+        ) -> Self {
+            Self {
+                T: payload.T,
+                Tp: payload.Tp,
+                Td: payload.Td,
+                L: payload.L.clone(),
+                O: payload.O.clone(),
+                device_id: device.map(|device| device.id),
+                device_name: device.map(|device| device.name.clone()),
+                ..Self::empty(kind, time)
+            }
+        }
+        /// Create a system entry with the given message
+        pub fn system(message: String, time: u32) -> Self {
+            Self {
+                message: Some(message),
+                ..Self::empty(AuditKind::System, time)
+            }
+        }
+        /// Create a checkpoint signing the given head of the audit log of the given user
+        pub fn checkpoint(user_id: &str, head: &str, key: &ServerKey, time: u32) -> Self {
+            Self {
+                signature: Some(key.sign(&checkpoint_message(user_id, head))),
+                ..Self::empty(AuditKind::Checkpoint, time)
+            }
+        }
+        /// Create an anchor for the audit log of the given user, whose entries up to the one
+        /// with the given hash were compacted away
+        pub fn anchor(user_id: &str, prev: &str, key: &ServerKey, time: u32) -> Self {
+            Self {
+                prev: Some(prev.to_string()),
+                signature: Some(key.sign(&anchor_message(user_id, prev))),
+                ..Self::empty(AuditKind::Anchor, time)
+            }
+        }
+    }
+
+    /// The hash the first entry of the log of the given user is chained to
+    pub fn genesis(user_id: &str) -> String {
+        signing::hash(&format!("audit:{}", user_id))
+    }
+    /// The message a checkpoint signs, it names the user so the log of one user
+    /// can't be passed off as the log of another
+    fn checkpoint_message(user_id: &str, head: &str) -> String {
+        format!("audit:{}:{}", user_id, head)
+    }
+    /// The message an anchor signs, it differs from the one of a checkpoint so the log can't be
+    /// cut off right before a checkpoint and passed off as compacted
+    fn anchor_message(user_id: &str, prev: &str) -> String {
+        format!("audit:{}:{}:anchor", user_id, prev)
+    }
+
+    /// Verifies the hash chain of the audit log of the given user, given as its stored
+    /// entries oldest first, and the signatures of its checkpoints. Reports the first entry
+    /// which breaks the chain.
+    /// The first entry has to be chained to the user id, unless older entries were compacted
+    /// away. Then the log starts with an anchor signed by the server, which names the hash
+    /// the next entry is chained to.
+    pub fn verify<I>(user_id: &str, key: &ServerKey, lines: I) -> AuditVerification
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut report = AuditVerification::default();
+        let mut prev_hash: Option<String> = None;
+        for line in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let index = report.entries;
+            report.entries += 1;
+            let record: AuditRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(_) => return report.broken(index, None, "The entry can't be read"),
+            };
+            if record.kind == AuditKind::Anchor {
+                if prev_hash.is_some() {
+                    return report.broken(
+                        index,
+                        Some(record.time),
+                        "The anchor is not at the start of the log",
+                    );
+                }
+                let signed = match (&record.prev, &record.signature) {
+                    (Some(prev), Some(signature)) => {
+                        key.verify(&anchor_message(user_id, prev), signature)
+                    }
+                    _ => false,
+                };
+                if !signed {
+                    return report.broken(
+                        index,
+                        Some(record.time),
+                        "The signature of the anchor is invalid",
+                    );
+                }
+                report.compacted = true;
+                report.last_checkpoint = Some(record.time);
+                prev_hash = record.prev;
+                continue;
+            }
+            match (&record.prev, &prev_hash) {
+                (None, _) => {
+                    return report.broken(index, Some(record.time), "The entry is not chained")
+                }
+                (Some(prev), None) if *prev != genesis(user_id) => {
+                    return report.broken(
+                        index,
+                        Some(record.time),
+                        "The start of the log is missing",
+                    )
+                }
+                (Some(prev), Some(expected)) if prev != expected => {
+                    return report.broken(
+                        index,
+                        Some(record.time),
+                        "The entry doesn't match the previous entry",
+                    )
+                }
+                _ => (),
+            }
+            if record.kind == AuditKind::Checkpoint {
+                let signed = match (&record.prev, &record.signature) {
+                    (Some(prev), Some(signature)) => {
+                        key.verify(&checkpoint_message(user_id, prev), signature)
+                    }
+                    _ => false,
+                };
+                if !signed {
+                    return report.broken(
+                        index,
+                        Some(record.time),
+                        "The signature of the checkpoint is invalid",
+                    );
+                }
+                report.last_checkpoint = Some(record.time);
+                report.unsigned = 0;
+            } else {
+                report.unsigned += 1;
+            }
+            prev_hash = Some(signing::hash(&line));
+        }
+        report
+    }
+
+    /// Removes the entries exceeding the retention of their log, purges the logs of users
+    /// archived for longer than the archive period and signs the heads of all logs written to
+    /// since their last checkpoint. Has to be called on the audit table.
+    /// Returns how many entries were compacted and purged and how many logs were signed.
+    pub fn maintain(
+        audit_db: &DatabaseState,
+        key: &ServerKey,
+        cfg: &ConfigAudit,
+        time: u32,
+    ) -> Result<(usize, usize, usize), rusqlite::Error> {
+        let compacted = audit_db.compact_audit(key, cfg.max_entries, cfg.max_age, time)?;
+        let purged = audit_db.purge_archived_audit(cfg.archive_period, time)?;
+        let mut signed = 0;
+        for user_id in audit_db.get_unsigned_audit_users()? {
+            if audit_db.checkpoint_audit(&user_id, key, time)? {
+                signed += 1;
+            }
+        }
+        Ok((compacted, purged, signed))
+    }
+
+    /// Spawns the thread maintaining the audit logs in the given database once every
+    /// interval (see `maintain`), so writing an entry never has to look at the whole log
+    pub fn spawn_maintainer(db_path: String, key: ServerKey, cfg: ConfigAudit, interval: Duration) {
+        thread::spawn(move || {
+            let audit_db = DatabaseState::init_with_table_name(db_path, "audit".to_string())
+                .expect("Failed to connect to database");
+            loop {
+                let time = Utc::now()
+                    .timestamp()
+                    .try_into()
+                    .expect("Time went backwards");
+                match maintain(&audit_db, &key, &cfg, time) {
+                    Ok((compacted, purged, signed)) if compacted + purged + signed > 0 => {
+                        log::debug!(
+                            "Compacted {} and purged {} audit entries, signed {} audit logs",
+                            compacted,
+                            purged,
+                            signed
+                        )
+                    }
+                    Ok(_) => (),
+                    Err(err) => log::error!("Failed to maintain audit logs!\n: {}", err),
+                }
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    /// Parses a line of a log in the format written before logs were stored as JSON Lines:
+    /// `%u %d - %l; %o` with the UTC time of the entry, the seconds since the client sent
+    /// the request, the location `[Latitude, Longitude, other]` and the extras
+    /// `{"key": ["values"], ...}`. Messages of the server were written as extras of the
+    /// type "SYSTEM MESSAGE", the device which made a request as the extra "DEVICE".
+    pub fn parse_legacy_line(line: &str) -> Option<AuditRecord> {
+        let (head, content) = line.split_once(" - ")?;
+        let (time, diff) = head.rsplit_once(' ')?;
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f UTC")
+            .ok()?
+            .and_utc()
+            .timestamp();
+        let time = u32::try_from(time).ok()?;
+        let diff: i64 = diff.parse().ok()?;
+        // The location and the extras were written in the debug format,
+        // which matches JSON for lists and maps of strings
+        let split = content.find("]; {")?;
+        let location: Vec<String> = serde_json::from_str(&content[..=split]).ok()?;
+        let mut extras: HashMap<String, Vec<String>> =
+            serde_json::from_str(&content[split + 3..]).ok()?;
+
+        if extras.get("TYPE") == Some(&vec!["SYSTEM MESSAGE".to_string()]) {
+            let message = extras.remove("MESSAGE")?.join(" ");
+            return Some(AuditRecord::system(message, time));
+        }
+        let mut record = AuditRecord::empty(AuditKind::Imported, time);
+        // Requests without a time were logged as sent at 0
+        record.T = u32::try_from(i64::from(time) - diff)
+            .ok()
+            .filter(|sent| *sent > 0);
+        if location != ["-"] {
+            record.L = Some(location);
+        }
+        if let Some(device) = extras.remove("DEVICE") {
+            record.device_name = device.first().cloned();
+            record.device_id = device.get(1).and_then(|id| id.parse().ok());
+        }
+        if !extras.is_empty() {
+            record.O = Some(extras);
+        }
+        Some(record)
+    }
+
+    /// Reads the entries of a log file written before the audit log was kept in the database.
+    /// Entries which can't be parsed are skipped. Checkpoints are dropped, as the entries are
+    /// chained and signed anew in the database.
+    fn read_log_file(
+        path: &Path,
+        user_id: &str,
+        key: &ServerKey,
+    ) -> Result<Vec<AuditRecord>, ServerError> {
+        let lines = BufReader::new(fs::File::open(path)?)
+            .lines()
+            .collect::<Result<Vec<String>, _>>()?;
+        let legacy = path.extension().and_then(|ext| ext.to_str()) == Some(LEGACY_EXTENSION);
+        if !legacy {
+            let report = verify(user_id, key, lines.iter().cloned());
+            if let Some(reason) = report.reason {
+                log::warn!(
+                    "The audit log {} is broken at entry {}, importing it anyway: {}",
+                    path.display(),
+                    report.broken_at.unwrap_or_default(),
+                    reason
+                );
+            }
+        }
+        let mut records = vec![];
+        let mut skipped = 0;
+        for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+            let record = if legacy {
+                parse_legacy_line(line)
+            } else {
+                serde_json::from_str::<AuditRecord>(line).ok()
+            };
+            match record {
+                Some(record) if record.kind == AuditKind::Checkpoint => (),
+                Some(record) => records.push(AuditRecord {
+                    prev: None,
+                    signature: None,
+                    ..record
+                }),
+                None => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            log::warn!(
+                "Skipped {} unreadable entries of the audit log {}",
+                skipped,
+                path.display()
+            );
+        }
+        Ok(records)
+    }
+
+    /// Imports the audit logs which were written to files in the given folder into the
+    /// database and deletes the files afterwards. Logs named after the token of their user,
+    /// which doubled as its id in the past, are imported for that user. Files which were
+    /// imported before but couldn't be deleted are only deleted.
+    /// Returns how many logs were imported.
+    pub fn import_log_files(
+        db: &DatabaseState,
+        logpath: &str,
+        key: &ServerKey,
+    ) -> Result<usize, ServerError> {
+        let entries = match fs::read_dir(logpath) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut paths = entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, _>>()?;
+        paths.retain(|path| {
+            matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some(LEGACY_EXTENSION) | Some(JSONL_EXTENSION)
+            )
+        });
+        // The legacy log of a user holds older entries than its JSON Lines log
+        paths.sort_by_key(|path| {
+            (
+                path.extension().and_then(|ext| ext.to_str()) != Some(LEGACY_EXTENSION),
+                path.clone(),
+            )
+        });
+        let time = Utc::now()
+            .timestamp()
+            .try_into()
+            .expect("Time went backwards");
+        let mut imported = 0;
+        for path in paths {
+            let file = match path.file_name().and_then(|file| file.to_str()) {
+                Some(file) => file,
+                None => continue,
+            };
+            let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => stem,
+                None => continue,
+            };
+            let user = match db.get_user_by_id(&stem.to_string())? {
+                Some(user) => Some(user),
+                None => db.get_user_by_token(stem)?,
+            };
+            let user = match user {
+                Some(user) => user,
+                None => {
+                    log::warn!(
+                        "The audit log {} belongs to no user, not importing it",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            let records = read_log_file(&path, &user.id, key)?;
+            if db.import_audit_entries(&user.id, file, records, time)? {
+                imported += 1;
+            } else {
+                log::warn!(
+                    "The audit log {} was imported before, deleting it",
+                    path.display()
+                );
+            }
+            fs::remove_file(&path)?;
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::audit_log::{self, AuditKind, AuditRecord};
+    use crate::data::data_forms::{
+        AuditQuery, ConfigAudit, Device, RequestPayload, TransitionCause, UserSettings, UserState,
+    };
+    use crate::data_handler::sqlite_handler::DatabaseState;
+    use crate::signature_handler::signing::{self, ServerKey};
+
+    use chrono::Utc;
+    use std::{collections::HashMap, convert::TryFrom, fs};
+
+    /// Opens the audit table of a database of its own, so the compaction in one test
+    /// doesn't remove the entries of another. The tables the retention is read from and the
+    /// imported files are recorded in are created as well.
+    fn audit_db(name: &str) -> (DatabaseState, String) {
+        let path = std::env::temp_dir()
+            .join(format!("dmnb_{}.sqlite", name))
+            .to_str()
+            .unwrap()
+            .to_string();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+        DatabaseState::init(path.clone())
+            .unwrap()
+            .create_table_for_user()
+            .unwrap();
+        DatabaseState::init_with_table_name(path.clone(), "state_history".to_string())
+            .unwrap()
+            .create_table_for_state_history()
+            .unwrap();
+        DatabaseState::init_with_table_name(path.clone(), "settings".to_string())
+            .unwrap()
+            .create_table_for_settings()
+            .unwrap();
+        DatabaseState::init_with_table_name(path.clone(), "audit_imports".to_string())
+            .unwrap()
+            .create_table_for_audit_imports()
+            .unwrap();
+        let db = DatabaseState::init_with_table_name(path.clone(), "audit".to_string()).unwrap();
+        db.create_table_for_audit().unwrap();
+        (db, path)
+    }
+
+    #[test]
+    fn append_and_compact() {
+        let (db, _) = audit_db("audit_test");
+
+        let mut extras = HashMap::new();
+        extras.insert("beacon".to_string(), vec!["phone".to_string()]);
+        let payload = RequestPayload {
+            T: Some(1600000000),
+            Tp: None,
+            Td: Some(3600),
+            L: Some(vec!["1.0".to_string(), "2.0".to_string()]),
+            O: Some(extras),
+        };
+        let device = Device {
+            id: 7,
+            user_id: "user".to_string(),
+            name: "phone".to_string(),
+            created: 1600000000,
+            last_seen: None,
+        };
+        let heartbeat =
+            AuditRecord::from_payload(AuditKind::Heartbeat, &payload, Some(&device), 1600000001);
+        db.append_audit("user", heartbeat.clone()).unwrap();
+        for i in 0..9 {
+            db.append_audit(
+                "user",
+                AuditRecord::system(format!("Message {}", i), 1600000002 + i),
+            )
+            .unwrap();
+        }
+        db.append_audit("other", AuditRecord::system("Other".to_string(), 0))
+            .unwrap();
+        let records = db.get_audit_records("user").unwrap();
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[0].O, heartbeat.O);
+        assert_eq!(records[0].L, heartbeat.L);
+        assert_eq!(records[0].device_name, Some("phone".to_string()));
+        assert_eq!(records[9].message, Some("Message 8".to_string()));
+        // Other users have their own log
+        assert_eq!(db.get_audit_records("other").unwrap().len(), 1);
+        assert!(db.get_audit_records("nobody").unwrap().is_empty());
+        assert_eq!(records[0].prev, Some(audit_log::genesis("user")));
+
+        let key = ServerKey::from_bytes([7; 32]);
+        let report = audit_log::verify("user", &key, db.get_audit_lines("user").unwrap());
+        assert_eq!(report.entries, 10);
+        assert!(!report.compacted);
+
+        // Only the last entries of every user are kept, an anchor takes the place of the others
+        assert_eq!(db.compact_audit(&key, 4, None, 0).unwrap(), 6);
+        assert_eq!(db.compact_audit(&key, 4, None, 0).unwrap(), 0);
+        let records = db.get_audit_records("user").unwrap();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].kind, AuditKind::Anchor);
+        assert_eq!(records[1].message, Some("Message 5".to_string()));
+        assert_eq!(records[4].message, Some("Message 8".to_string()));
+        assert_eq!(db.get_audit_records("other").unwrap().len(), 1);
+        // Appending continues after the compacted entries
+        db.append_audit("user", AuditRecord::system("Last".to_string(), 0))
+            .unwrap();
+        assert_eq!(db.get_audit_records("user").unwrap().len(), 6);
+        let mut cfg = ConfigAudit {
+            max_entries: 4,
+            ..ConfigAudit::default()
+        };
+        assert_eq!(audit_log::maintain(&db, &key, &cfg, 0).unwrap(), (1, 0, 2));
+        // Signed heads aren't signed again
+        cfg.max_entries = 5;
+        assert_eq!(audit_log::maintain(&db, &key, &cfg, 0).unwrap(), (0, 0, 0));
+        // The compacted log is still a valid chain, starting at the anchor
+        let lines = db.get_audit_lines("user").unwrap();
+        let report = audit_log::verify("user", &key, lines.clone());
+        assert_eq!(report.entries, 6);
+        assert!(report.compacted);
+        assert_eq!(report.broken_at, None);
+        assert_eq!(report.unsigned, 0);
+        // Without the anchor the start of the log is missing
+        let report = audit_log::verify("user", &key, lines[1..].to_vec());
+        assert_eq!(report.broken_at, Some(0));
+
+        // Once every entry was compacted away, new entries are chained to the anchor
+        assert_eq!(db.compact_audit(&key, 4, Some(0), 1).unwrap(), 5 + 2);
+        assert_eq!(audit_log::maintain(&db, &key, &cfg, 1).unwrap(), (0, 0, 0));
+        db.append_audit("user", AuditRecord::system("Next".to_string(), 2))
+            .unwrap();
+        let report = audit_log::verify("user", &key, db.get_audit_lines("user").unwrap());
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.broken_at, None);
+        assert_eq!(report.unsigned, 1);
+    }
+
+    #[test]
+    fn query_entries() {
+        let (db, _) = audit_db("audit_query_test");
+
+        let payload = RequestPayload {
+            T: Some(100),
+            Tp: None,
+            Td: None,
+            L: None,
+            O: None,
+        };
+        for time in 100..110 {
+            let record = if time % 2 == 0 {
+                AuditRecord::from_payload(AuditKind::Heartbeat, &payload, None, time)
+            } else {
+                AuditRecord::system(format!("At {}", time), time)
+            };
+            db.append_audit("user", record).unwrap();
+        }
+
+        // Newest first, the default page holds all entries
+        let page = db.query_audit("user", &AuditQuery::default()).unwrap();
+        assert_eq!(page.total, 10);
+        assert_eq!(page.entries.len(), 10);
+        assert_eq!(page.entries[0].time, 109);
+
+        let query = AuditQuery {
+            from: Some(102),
+            to: Some(107),
+            kind: Some(AuditKind::Heartbeat),
+            offset: 1,
+            limit: Some(1),
+        };
+        let page = db.query_audit("user", &query).unwrap();
+        // Heartbeats at 106, 104 and 102, the page skips the first one
+        assert_eq!(page.total, 3);
+        assert_eq!(page.offset, 1);
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].time, 104);
+
+        // Users without a log have no entries
+        let page = db.query_audit("other", &AuditQuery::default()).unwrap();
+        assert_eq!(page.total, 0);
+        assert!(page.entries.is_empty());
+    }
+
+    #[test]
+    fn hash_chain() {
+        let (db, _) = audit_db("audit_chain_test");
+        let key = ServerKey::from_bytes([7; 32]);
+
+        for time in 1..6 {
+            db.append_audit("user", AuditRecord::system(time.to_string(), time))
+                .unwrap();
+        }
+        assert!(db.checkpoint_audit("user", &key, 6).unwrap());
+        // A signed head isn't signed again, neither is an empty log
+        assert!(!db.checkpoint_audit("user", &key, 7).unwrap());
+        assert!(!db.checkpoint_audit("other", &key, 7).unwrap());
+        db.append_audit("user", AuditRecord::system("7".to_string(), 7))
+            .unwrap();
+
+        let lines = db.get_audit_lines("user").unwrap();
+        let report = audit_log::verify("user", &key, lines.clone());
+        assert_eq!(report.entries, 7);
+        assert!(!report.compacted);
+        assert_eq!(report.unsigned, 1);
+        assert_eq!(report.last_checkpoint, Some(6));
+        assert_eq!(report.broken_at, None);
+
+        // Checkpoints signed by another key are rejected
+        let other = ServerKey::from_bytes([8; 32]);
+        let report = audit_log::verify("user", &other, lines.clone());
+        assert_eq!(report.broken_at, Some(5));
+        // The log of one user can't be passed off as the log of another
+        let report = audit_log::verify("other", &key, lines.clone());
+        assert_eq!(report.broken_at, Some(0));
+
+        // Changing an entry breaks the link of the next one
+        let mut changed = lines.clone();
+        changed[2] = changed[2].replace("\"message\":\"3\"", "\"message\":\"x\"");
+        let report = audit_log::verify("user", &key, changed);
+        assert_eq!(report.broken_at, Some(3));
+        assert_eq!(report.broken_time, Some(4));
+        // So does removing an entry
+        let mut removed = lines.clone();
+        removed.remove(2);
+        let report = audit_log::verify("user", &key, removed);
+        assert_eq!(report.broken_at, Some(2));
+        // Or removing the start of the log, even up to a checkpoint
+        for start in [1, 5] {
+            let report = audit_log::verify("user", &key, lines[start..].to_vec());
+            assert_eq!(report.broken_at, Some(0));
+            assert!(!report.compacted);
+        }
+        // Entries written without being chained are rejected
+        let unchained: Vec<String> = lines
+            .iter()
+            .map(|line| {
+                let record: AuditRecord = serde_json::from_str(line).unwrap();
+                serde_json::to_string(&AuditRecord {
+                    prev: None,
+                    ..record
+                })
+                .unwrap()
+            })
+            .collect();
+        let report = audit_log::verify("user", &key, unchained);
+        assert_eq!(report.broken_at, Some(0));
+
+        // Only an anchor signed by the server may take the place of the removed start
+        let hash = signing::hash(&lines[1]);
+        for (signer, broken_at) in [(&other, Some(0)), (&key, None)] {
+            let mut compacted =
+                vec![
+                    serde_json::to_string(&AuditRecord::anchor("user", &hash, signer, 8)).unwrap(),
+                ];
+            compacted.extend_from_slice(&lines[2..]);
+            let report = audit_log::verify("user", &key, compacted);
+            assert_eq!(report.broken_at, broken_at);
+        }
+    }
+
+    #[test]
+    fn retention() {
+        let (db, path) = audit_db("audit_retention_test");
+        for user_id in ["user", "young", "few"] {
+            for time in 1..=10 {
+                db.append_audit(user_id, AuditRecord::system(time.to_string(), time * 100))
+                    .unwrap();
+            }
+        }
+        // Users may keep fewer or younger entries than the server
+        let settings_db =
+            DatabaseState::init_with_table_name(path.clone(), "settings".to_string()).unwrap();
+        settings_db
+            .set_settings(&UserSettings {
+                audit_max_age: Some(300),
+                ..UserSettings::default_for("young")
+            })
+            .unwrap();
+        settings_db
+            .set_settings(&UserSettings {
+                audit_entries: Some(2),
+                ..UserSettings::default_for("few")
+            })
+            .unwrap();
+
+        // Without a maximum age, only the number of entries counts.
+        // Every compacted log starts with an anchor, which isn't counted.
+        let key = ServerKey::from_bytes([7; 32]);
+        assert_eq!(db.compact_audit(&key, 8, None, 1000).unwrap(), 2 + 6 + 8);
+        assert_eq!(db.get_audit_records("user").unwrap().len(), 1 + 8);
+        let young = db.get_audit_records("young").unwrap();
+        assert_eq!(young.len(), 1 + 4);
+        assert_eq!(young[1].time, 700);
+        assert_eq!(db.get_audit_records("few").unwrap().len(), 1 + 2);
+        // The younger of both maximum ages applies
+        assert_eq!(db.compact_audit(&key, 8, Some(500), 1000).unwrap(), 2);
+        assert_eq!(db.get_audit_records("user").unwrap()[1].time, 500);
+        assert_eq!(db.compact_audit(&key, 8, Some(100), 1000).unwrap(), 4 + 2);
+        assert_eq!(db.get_audit_records("young").unwrap().len(), 1 + 2);
+        for user_id in ["user", "young", "few"] {
+            let report = audit_log::verify(user_id, &key, db.get_audit_lines(user_id).unwrap());
+            assert_eq!(report.broken_at, None);
+        }
+
+        // The log of a user is purged once the archive period after notifying the contacts passed
+        let users = DatabaseState::init(path).unwrap();
+        let (dead, _) = users.new_user(&"dead@example.com".to_string()).unwrap();
+        let (alive, _) = users.new_user(&"alive@example.com".to_string()).unwrap();
+        for state in [UserState::Deceased, UserState::Notified] {
+            users
+                .update_state_user(&dead.id, state, TransitionCause::Escalation)
+                .unwrap()
+                .unwrap();
+        }
+        for user_id in [&dead.id, &alive.id] {
+            db.append_audit(user_id, AuditRecord::system("Last".to_string(), 0))
+                .unwrap();
+        }
+        let now = u32::try_from(Utc::now().timestamp()).unwrap();
+        assert_eq!(db.purge_archived_audit(3600, now).unwrap(), 0);
+        assert_eq!(db.purge_archived_audit(3600, now + 3600).unwrap(), 1);
+        assert!(db.get_audit_records(&dead.id).unwrap().is_empty());
+        assert_eq!(db.get_audit_records(&alive.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn import_legacy_logs() {
+        let record = audit_log::parse_legacy_line(
+            "2021-05-01 12:00:00.123456789 UTC 5 - [\"1.0\", \"2.0\"]; {\"beacon\": [\"phone\"], \"DEVICE\": [\"phone\", \"7\"]}",
+        )
+        .unwrap();
+        assert_eq!(record.kind, AuditKind::Imported);
+        assert_eq!(record.time, 1619870400);
+        assert_eq!(record.T, Some(1619870395));
+        assert_eq!(record.L, Some(vec!["1.0".to_string(), "2.0".to_string()]));
+        assert_eq!(record.device_name, Some("phone".to_string()));
+        assert_eq!(record.device_id, Some(7));
+        let mut extras = HashMap::new();
+        extras.insert("beacon".to_string(), vec!["phone".to_string()]);
+        assert_eq!(record.O, Some(extras));
+        // Requests without a time or a location
+        let record =
+            audit_log::parse_legacy_line("2021-05-01 12:00:00 UTC 1619870400 - [\"-\"]; {}")
+                .unwrap();
+        assert_eq!((record.T, record.L, record.O), (None, None, None));
+        // Messages of the server
+        let record = audit_log::parse_legacy_line(
+            "2021-05-01 12:00:00.5 UTC -1 - [\"-\"]; {\"TYPE\": [\"SYSTEM MESSAGE\"], \"MESSAGE\": [\"Token was revoked\"]}",
+        )
+        .unwrap();
+        assert_eq!(
+            record,
+            AuditRecord::system("Token was revoked".to_string(), 1619870400)
+        );
+        assert_eq!(audit_log::parse_legacy_line(""), None);
+        assert_eq!(
+            audit_log::parse_legacy_line("2021-05-01 12:00:00 UTC"),
+            None
+        );
+
+        let (audit_db, path) = audit_db("audit_import_test");
+        let db = DatabaseState::init(path.clone()).unwrap();
+        db.create_table_for_user().unwrap();
+        DatabaseState::init_with_table_name(path, "state_history".to_string())
+            .unwrap()
+            .create_table_for_state_history()
+            .unwrap();
+        let (user, token) = db.new_user(&"audit@example.com".to_string()).unwrap();
+        let folder = std::env::temp_dir().join("dmnb_audit_import_test/");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let logpath = folder.to_str().unwrap();
+        // Logs named after the token, with blank and broken lines
+        fs::write(
+            folder.join(format!("{}.log", token)),
+            "2021-05-01 12:00:00 UTC 5 - [\"-\"]; {}\n\n\ngarbage\n2021-05-01 12:00:01 UTC -1 - [\"-\"]; {\"TYPE\": [\"SYSTEM MESSAGE\"], \"MESSAGE\": [\"Hello\"]}\n",
+        )
+        .unwrap();
+        // JSON Lines logs named after the id are imported after them, without checkpoints
+        fs::write(
+            folder.join(format!("{}.jsonl", user.id)),
+            "{\"time\":1619870402,\"kind\":\"heartbeat\"}\n{\"time\":1619870403,\"kind\":\"checkpoint\",\"signature\":\"00\"}\n",
+        )
+        .unwrap();
+        // Logs of unknown users are left alone
+        fs::write(folder.join("unknown.log"), "").unwrap();
+
+        let key = ServerKey::from_bytes([7; 32]);
+        assert_eq!(audit_log::import_log_files(&db, logpath, &key).unwrap(), 2);
+        let records = audit_db.get_audit_records(&user.id).unwrap();
+        let kinds: Vec<AuditKind> = records.iter().map(|record| record.kind).collect();
+        assert_eq!(
+            kinds,
+            [AuditKind::Imported, AuditKind::System, AuditKind::Heartbeat]
+        );
+        assert_eq!(records[1].message, Some("Hello".to_string()));
+        let report = audit_log::verify(&user.id, &key, audit_db.get_audit_lines(&user.id).unwrap());
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.broken_at, None);
+        assert!(!folder.join(format!("{}.log", token)).exists());
+        assert!(folder.join("unknown.log").exists());
+        // Imported logs are gone, importing again does nothing
+        assert_eq!(audit_log::import_log_files(&db, logpath, &key).unwrap(), 0);
+        // Neither does a log which couldn't be deleted after it was imported
+        fs::write(
+            folder.join(format!("{}.jsonl", user.id)),
+            "{\"time\":1619870402,\"kind\":\"heartbeat\"}\n",
+        )
+        .unwrap();
+        assert_eq!(audit_log::import_log_files(&db, logpath, &key).unwrap(), 0);
+        assert_eq!(audit_db.get_audit_records(&user.id).unwrap().len(), 3);
+        assert!(!folder.join(format!("{}.jsonl", user.id)).exists());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
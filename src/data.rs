@@ -3,6 +3,9 @@
 pub mod data_forms {
     #![allow(non_snake_case)]
 
+    use crate::audit::audit_log::{AuditKind, AuditRecord};
+    use crate::error::server_error::{ErrorCode, ErrorDescription};
+
     use serde_derive::{Deserialize, Serialize};
     use std::collections::HashMap;
     use std::fmt;
@@ -10,10 +13,20 @@ pub mod data_forms {
 
     // Config structs
     #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(default)]
     pub struct ConfigFile {
         pub log_folder: String,
         pub database_path: String,
         pub email_body_scheme: String,
+        pub verification_body_scheme: String,
+        pub warning_body_scheme: String,
+        pub admin_body_scheme: String,
+        pub contact_body_scheme: String,
+        pub revival_body_scheme: String,
+        pub false_alarm_body_scheme: String,
+        pub acknowledge_page_scheme: String,
+        pub email_change_body_scheme: String,
+        pub server_key: String,
     }
     impl ::std::default::Default for ConfigFile {
         fn default() -> Self {
@@ -21,26 +34,117 @@ pub mod data_forms {
                 log_folder: "./rsc/auditlogs/".to_string(),
                 database_path: "./rsc/dmnb.sqlite".to_string(),
                 email_body_scheme: "./rsc/email_body.html".to_string(),
+                verification_body_scheme: "./rsc/verification_body.html".to_string(),
+                warning_body_scheme: "./rsc/warning_body.html".to_string(),
+                admin_body_scheme: "./rsc/admin_body.html".to_string(),
+                contact_body_scheme: "./rsc/contact_body.html".to_string(),
+                revival_body_scheme: "./rsc/revival_body.html".to_string(),
+                false_alarm_body_scheme: "./rsc/false_alarm_body.html".to_string(),
+                acknowledge_page_scheme: "./rsc/acknowledge_page.html".to_string(),
+                email_change_body_scheme: "./rsc/email_change_body.html".to_string(),
+                server_key: "./rsc/server.key".to_string(),
             }
         }
     }
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
     pub struct ConfigMain {
+        #[serde(default)]
+        pub server_config: ConfigServer,
         pub file_locations: ConfigFile,
         pub smtp_config: ConfigSmtp,
+        #[serde(default)]
+        pub alarm_config: ConfigAlarm,
+        #[serde(default)]
+        pub rate_limit_config: ConfigRateLimit,
+        #[serde(default)]
+        pub audit_config: ConfigAudit,
+    }
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(default)]
+    pub struct ConfigServer {
+        /// The URL under which this server is reachable from the outside, used to build links
+        pub public_url: String,
+        /// Seconds the old token of a user stays valid after it was rotated
+        pub token_overlap: u32,
     }
-    impl ::std::default::Default for ConfigMain {
+    impl ::std::default::Default for ConfigServer {
         fn default() -> Self {
             Self {
-                file_locations: ConfigFile::default(),
-                smtp_config: ConfigSmtp::default(),
+                public_url: "http://127.0.0.1:3030".to_string(),
+                token_overlap: 300,
             }
         }
     }
     #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(default)]
+    pub struct ConfigAlarm {
+        /// Seconds between warning an overdue user and notifying their contacts
+        pub warn_grace_period: u32,
+        /// Seconds between notifying the contacts and notifying the admin, if nobody acknowledged
+        pub admin_grace_period: u32,
+    }
+    impl ::std::default::Default for ConfigAlarm {
+        fn default() -> Self {
+            Self {
+                warn_grace_period: 3600,
+                admin_grace_period: 86400,
+            }
+        }
+    }
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(default)]
+    pub struct ConfigRateLimit {
+        /// Seconds after which the request counters are reset
+        pub window: u32,
+        /// Requests a single IP may make to the register, verify and revive endpoints per window
+        pub requests_per_ip: u32,
+        /// Requests to these endpoints which may concern a single email per window
+        pub requests_per_email: u32,
+        /// Failed attempts after which a verification code is invalidated
+        pub max_attempts: u32,
+        /// Whether the client IP is taken from the Forwarded/X-Forwarded-For headers,
+        /// only enable this if the server is behind a reverse proxy setting them
+        pub trust_forwarded_for: bool,
+    }
+    impl ::std::default::Default for ConfigRateLimit {
+        fn default() -> Self {
+            Self {
+                window: 600,
+                requests_per_ip: 20,
+                requests_per_email: 5,
+                max_attempts: 5,
+                trust_forwarded_for: false,
+            }
+        }
+    }
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(default)]
+    pub struct ConfigAudit {
+        /// The number of entries kept in the audit log of a user, older entries are compacted away
+        pub max_entries: u32,
+        /// Seconds after which entries are compacted away, None keeps them until the log is full.
+        /// Users may choose to keep fewer or younger entries, see `UserSettings`
+        pub max_age: Option<u32>,
+        /// Seconds the audit log of a user is kept after their contacts were notified
+        pub archive_period: u32,
+    }
+    impl ::std::default::Default for ConfigAudit {
+        fn default() -> Self {
+            Self {
+                max_entries: 250,
+                max_age: None,
+                archive_period: 90 * 86400,
+            }
+        }
+    }
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(default)]
     pub struct ConfigSmtp {
         pub admin_mail_addr: Option<String>,
+        pub sender_mail_addr: String,
         pub smtp_server: String,
+        pub smtp_port: u16,
+        pub smtp_security: SmtpSecurity,
         pub smtp_username: String,
         pub smtp_password: String,
     }
@@ -48,12 +152,26 @@ pub mod data_forms {
         fn default() -> Self {
             Self {
                 admin_mail_addr: None,
+                sender_mail_addr: "dmnb@localhost".to_string(),
                 smtp_server: "127.0.0.1".to_string(),
+                smtp_port: 587,
+                smtp_security: SmtpSecurity::StartTls,
                 smtp_username: "test".to_string(),
                 smtp_password: "test".to_string(),
             }
         }
     }
+    /// The transport security used to connect to the SMTP server.
+    /// none: plaintext (only use this for local relays)
+    /// starttls: upgrade a plaintext connection via STARTTLS
+    /// tls: connect via implicit TLS
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum SmtpSecurity {
+        None,
+        StartTls,
+        Tls,
+    }
 
     // Request Payload
     #[derive(Serialize, Deserialize)]
@@ -65,10 +183,67 @@ pub mod data_forms {
         pub O: Option<HashMap<String, Vec<String>>>,
     }
     impl RequestPayload {
-        /// Tries to create a Payload from a given string
-        /// the string has to be encoded as JSON otherwise this function will panic!
-        pub fn from_json(content: &String) -> Self {
-            serde_json::from_str(content).unwrap()
+        /// Tries to create a Payload from a given string encoded as JSON
+        pub fn from_json(content: &str) -> serde_json::Result<Self> {
+            serde_json::from_str(content)
+        }
+        /// Returns the first value of the given key in the extras (`O`) of the payload
+        pub fn get_extra(&self, key: &str) -> Option<&String> {
+            self.O.as_ref()?.get(key)?.first()
+        }
+    }
+
+    /// The filters of an audit log query, given in the query string of `/api/audit`
+    /// from: Only entries written at or after this timestamp
+    /// to: Only entries written at or before this timestamp
+    /// kind: Only entries of this kind (audit, sign, heartbeat, system, checkpoint or imported)
+    /// offset: The number of matching entries to skip, newest first
+    /// limit: The maximum number of entries to return
+    #[derive(Deserialize, Debug, Clone, Default)]
+    #[serde(default)]
+    pub struct AuditQuery {
+        pub from: Option<u32>,
+        pub to: Option<u32>,
+        pub kind: Option<AuditKind>,
+        pub offset: usize,
+        pub limit: Option<usize>,
+    }
+    /// A page of the audit log of a user, newest entry first
+    /// total: The number of entries matching the query, across all pages
+    /// offset: The number of matching entries skipped before this page
+    /// entries: The entries of this page
+    #[derive(Serialize, PartialEq, Debug, Clone)]
+    pub struct AuditEntries {
+        pub total: usize,
+        pub offset: usize,
+        pub entries: Vec<AuditRecord>,
+    }
+
+    /// The result of verifying the hash chain of the audit log of a user
+    /// entries: The number of entries read, up to the first broken one
+    /// compacted: Whether older entries were compacted away, the log starts with an anchor signed by the server
+    /// unsigned: The number of entries after the last checkpoint, not yet signed by the server
+    /// last_checkpoint: The time of the last checkpoint or anchor, None if the log was never signed
+    /// broken_at: The index (oldest entry first) of the first entry breaking the chain, None if the log is intact
+    /// broken_time: The time written in that entry, if it could be read
+    /// reason: Why that entry breaks the chain
+    #[derive(Serialize, PartialEq, Debug, Clone, Default)]
+    pub struct AuditVerification {
+        pub entries: usize,
+        pub compacted: bool,
+        pub unsigned: usize,
+        pub last_checkpoint: Option<u32>,
+        pub broken_at: Option<usize>,
+        pub broken_time: Option<u32>,
+        pub reason: Option<String>,
+    }
+    impl AuditVerification {
+        /// Marks the entry at the given index as the first broken one
+        pub fn broken(mut self, index: usize, time: Option<u32>, reason: &str) -> Self {
+            self.broken_at = Some(index);
+            self.broken_time = time;
+            self.reason = Some(reason.to_string());
+            self
         }
     }
 
@@ -76,17 +251,40 @@ pub mod data_forms {
     #[derive(Serialize)]
     pub struct ResponsePayload {
         status: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
         content: ResponsePayloadTypes,
     }
     impl ResponsePayload {
         /// Create a new ResponsePayload with given Status code and given content
         pub fn new(status: u16, content: ResponsePayloadTypes) -> Self {
-            Self { status, content }
+            Self {
+                status,
+                code: None,
+                content,
+            }
+        }
+        /// Returns the status code of this payload
+        pub fn status(&self) -> u16 {
+            self.status
+        }
+        /// Create a new ResponsePayload for the given error, with its status and default message
+        pub fn error(code: ErrorCode) -> Self {
+            Self::error_with_message(code, code.message().to_string())
+        }
+        /// Create a new ResponsePayload for the given error with a more specific message
+        pub fn error_with_message(code: ErrorCode, message: String) -> Self {
+            Self {
+                status: code.status(),
+                code: Some(code),
+                content: ResponsePayloadTypes::Message(message),
+            }
         }
         /// Create a new ResponsePayload with given Status code and given Message string
         pub fn new_message(status: u16, message: String) -> Self {
             Self {
                 status,
+                code: None,
                 content: ResponsePayloadTypes::Message(message),
             }
         }
@@ -94,6 +292,7 @@ pub mod data_forms {
         pub fn new_static_message(status: u16, message: &str) -> Self {
             Self {
                 status,
+                code: None,
                 content: ResponsePayloadTypes::Message(message.to_string()),
             }
         }
@@ -101,31 +300,34 @@ pub mod data_forms {
         pub fn status_200() -> Self {
             Self {
                 status: 200,
+                code: None,
                 content: ResponsePayloadTypes::Message("Ok".to_string()),
             }
         }
         /// Create a new ResponsePayload with Status 400 and standardized message
         pub fn status_400() -> Self {
-            Self {
-                status: 400,
-                content: ResponsePayloadTypes::Message("Bad Request".to_string()),
-            }
+            Self::error(ErrorCode::InvalidInput)
         }
         /// Create a new ResponsePayload with Status 500 and standardized message
         pub fn status_500() -> Self {
-            Self {
-                status: 500,
-                content: ResponsePayloadTypes::Message(
-                    "Internal Server Error\nPlease try again later".to_string(),
-                ),
-            }
+            Self::error(ErrorCode::InternalError)
         }
     }
     #[derive(Serialize)]
     pub enum ResponsePayloadTypes {
         Message(String),
         User(User), // As defined in src/data_handler.rs
+        Credentials(Credentials),
+        Settings(UserSettings),
+        Contact(Contact),
+        Contacts(Vec<Contact>),
+        Device(DeviceCredentials),
+        Devices(Vec<Device>),
+        History(Vec<StateChange>),
         Status(ServerStatus),
+        Errors(Vec<ErrorDescription>),
+        Audit(AuditEntries),
+        AuditVerification(AuditVerification),
     }
 
     // Server Status
@@ -158,14 +360,212 @@ pub mod data_forms {
     }
 
     /// The User Object, as it's displayed in the database.
-    /// id: A unique identifier also used as the api-key or 'username'
+    /// id: A unique public identifier, the secret token of the user is stored separately as a hash
     /// email: used for notification and sign up
-    /// state: The state of the user: -1 Unknown, 0 Normal, 10 Deceased, 15  Deceased and Notified (aka. completed)
+    /// state: The state of the user, see `UserState`
     #[derive(Serialize, PartialEq, Debug, Clone)]
     pub struct User {
         pub id: String,
         pub email: String,
-        pub state: i8,
+        pub state: UserState,
+    }
+
+    /// The credentials of a user, these are only handed out when a token is created
+    /// user: The user the token belongs to
+    /// token: The secret API token (`Auth-Token`/`User-Token` header), the server only keeps its hash
+    #[derive(Serialize, PartialEq, Debug, Clone)]
+    pub struct Credentials {
+        pub user: User,
+        pub token: String,
+    }
+
+    /// The state of a user. In the database the state is stored as the number in brackets,
+    /// in JSON as the lowercase name.
+    /// Unknown (-1): Not a valid state of an existing user
+    /// Active (0): The user is monitored normally
+    /// Paused (1): The user paused monitoring, missed deadlines are ignored
+    /// Overdue (5): The user missed a deadline, an alarm is running
+    /// Deceased (10): The user is considered deceased, the contacts are being notified
+    /// Notified (15): The user is deceased and the contacts were notified (aka. completed)
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+    #[serde(rename_all = "lowercase")]
+    pub enum UserState {
+        Unknown,
+        Active,
+        Paused,
+        Overdue,
+        Deceased,
+        Notified,
+    }
+    impl UserState {
+        /// Returns the number used to store this state in the database
+        pub fn to_i8(self) -> i8 {
+            match self {
+                UserState::Unknown => -1,
+                UserState::Active => 0,
+                UserState::Paused => 1,
+                UserState::Overdue => 5,
+                UserState::Deceased => 10,
+                UserState::Notified => 15,
+            }
+        }
+        /// Returns the state stored as the given number in the database,
+        /// or None if the number is no valid state
+        pub fn from_i8(value: i8) -> Option<Self> {
+            match value {
+                -1 => Some(UserState::Unknown),
+                0 => Some(UserState::Active),
+                1 => Some(UserState::Paused),
+                5 => Some(UserState::Overdue),
+                10 => Some(UserState::Deceased),
+                15 => Some(UserState::Notified),
+                _ => None,
+            }
+        }
+        /// Whether the user is considered deceased (Deceased or Notified)
+        pub fn is_deceased(self) -> bool {
+            matches!(self, UserState::Deceased | UserState::Notified)
+        }
+        /// The transition table, returns whether a user in this state may change to the given state
+        /// for the given cause. Heartbeats and alarms may repeat the living states (Active, Paused,
+        /// Overdue), a deceased user can only be notified about or revived, which makes them active.
+        pub fn can_transition_to(self, next: UserState, cause: TransitionCause) -> bool {
+            use UserState::*;
+            // Only a revival brings a deceased user back, and it does nothing else
+            if cause == TransitionCause::Revival {
+                return self.is_deceased() && next == Active;
+            }
+            matches!(
+                (self, next),
+                (Active, Active | Paused | Overdue | Deceased)
+                    | (Paused, Active | Paused | Deceased)
+                    | (Overdue, Active | Paused | Overdue | Deceased)
+                    | (Deceased, Notified)
+            )
+        }
+        /// Checks the transition to the given state for the given cause against the transition
+        /// table, see `can_transition_to`
+        pub fn transition(
+            self,
+            next: UserState,
+            cause: TransitionCause,
+        ) -> Result<UserState, TransitionError> {
+            if self.can_transition_to(next, cause) {
+                Ok(next)
+            } else {
+                Err(TransitionError {
+                    from: self,
+                    to: next,
+                })
+            }
+        }
+    }
+    impl fmt::Display for UserState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let name = match self {
+                UserState::Unknown => "unknown",
+                UserState::Active => "active",
+                UserState::Paused => "paused",
+                UserState::Overdue => "overdue",
+                UserState::Deceased => "deceased",
+                UserState::Notified => "notified",
+            };
+            write!(f, "{}", name)
+        }
+    }
+
+    /// What caused a state change of a user
+    /// registration: The account was created
+    /// timeout: The user missed a deadline
+    /// sign: The user signed as deceased
+    /// ilive: The user sent a heartbeat
+    /// pause: The user paused or resumed monitoring
+    /// escalation: The alarm was escalated
+    /// revival: The user proved to be alive after being marked as deceased
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TransitionCause {
+        Registration,
+        Timeout,
+        Sign,
+        Ilive,
+        Pause,
+        Escalation,
+        Revival,
+    }
+    impl TransitionCause {
+        /// Returns the name used to store this cause in the database
+        pub fn as_str(self) -> &'static str {
+            match self {
+                TransitionCause::Registration => "registration",
+                TransitionCause::Timeout => "timeout",
+                TransitionCause::Sign => "sign",
+                TransitionCause::Ilive => "ilive",
+                TransitionCause::Pause => "pause",
+                TransitionCause::Escalation => "escalation",
+                TransitionCause::Revival => "revival",
+            }
+        }
+        /// Returns the cause stored as the given name in the database,
+        /// or None if the name is no valid cause
+        pub fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "registration" => Some(TransitionCause::Registration),
+                "timeout" => Some(TransitionCause::Timeout),
+                "sign" => Some(TransitionCause::Sign),
+                "ilive" => Some(TransitionCause::Ilive),
+                "pause" => Some(TransitionCause::Pause),
+                "escalation" => Some(TransitionCause::Escalation),
+                "revival" => Some(TransitionCause::Revival),
+                _ => None,
+            }
+        }
+    }
+
+    /// A StateChange object, as it's displayed in the database.
+    /// user_id: The id of the user whose state changed
+    /// old_state, new_state: The state before and after the change
+    /// cause: What caused the change
+    /// timestamp: A timestamp indicating when the state changed
+    #[derive(Serialize, PartialEq, Debug, Clone)]
+    pub struct StateChange {
+        pub user_id: String,
+        pub old_state: UserState,
+        pub new_state: UserState,
+        pub cause: TransitionCause,
+        pub timestamp: u32,
+    }
+
+    /// A state change which is not allowed by the transition table of `UserState`
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    pub struct TransitionError {
+        pub from: UserState,
+        pub to: UserState,
+    }
+    impl fmt::Display for TransitionError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.from {
+                UserState::Deceased | UserState::Notified => {
+                    write!(f, "You are marked as deceased")
+                }
+                UserState::Unknown => write!(f, "The user does not exist"),
+                _ => write!(
+                    f,
+                    "The state can't be changed from {} to {}",
+                    self.from, self.to
+                ),
+            }
+        }
+    }
+    impl std::error::Error for TransitionError {}
+    impl TransitionError {
+        /// The error code a client receives for this transition
+        pub fn code(&self) -> ErrorCode {
+            match self.from {
+                UserState::Deceased | UserState::Notified => ErrorCode::Deceased,
+                _ => ErrorCode::InvalidTransition,
+            }
+        }
     }
 
     impl fmt::Display for User {
@@ -180,11 +580,128 @@ pub mod data_forms {
             Self {
                 id: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
                 email: "".to_string(),
-                state: 10,
+                state: UserState::Deceased,
             } // An Empty or non-existent user / test user is ALWAYS deceased
         }
     }
 
+    /// The UserSettings Object, as it's displayed in the database.
+    /// id: The id of the user these settings belong to
+    /// interval: The default check-in interval in seconds, used if a heartbeat contains no `Td`
+    /// policy: How the deadlines of multiple beacons decide whether the user is overdue
+    /// audit_entries: The number of entries kept in the audit log, None for the server default
+    /// audit_max_age: Seconds after which audit entries are removed, None for the server default
+    #[derive(Serialize, PartialEq, Debug, Clone)]
+    pub struct UserSettings {
+        pub id: String,
+        pub interval: Option<u32>,
+        pub policy: BeaconPolicy,
+        pub audit_entries: Option<u32>,
+        pub audit_max_age: Option<u32>,
+    }
+    impl UserSettings {
+        /// Returns the default settings for the given user id
+        pub fn default_for(id: &str) -> Self {
+            Self {
+                id: id.to_string(),
+                interval: None,
+                policy: BeaconPolicy::Any,
+                audit_entries: None,
+                audit_max_age: None,
+            }
+        }
+    }
+
+    /// Every beacon of a user (like a phone or a watch) has its own deadline
+    /// any: The user is alive as long as any beacon checked in in time
+    /// all: The user is overdue as soon as one beacon missed its deadline
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+    #[serde(rename_all = "lowercase")]
+    pub enum BeaconPolicy {
+        Any,
+        All,
+    }
+    impl BeaconPolicy {
+        /// Returns the name used to store this policy in the database
+        pub fn as_str(self) -> &'static str {
+            match self {
+                BeaconPolicy::Any => "any",
+                BeaconPolicy::All => "all",
+            }
+        }
+        /// Returns the policy stored as the given name in the database,
+        /// or None if the name is no valid policy
+        pub fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "any" => Some(BeaconPolicy::Any),
+                "all" => Some(BeaconPolicy::All),
+                _ => None,
+            }
+        }
+    }
+
+    /// The Contact object, as it's displayed in the database.
+    /// id: A unique number identifying the contact
+    /// user_id: The id of the user this contact belongs to
+    /// name: The name of the contact
+    /// email: The address the contact is notified at, once the user is deceased
+    /// message: A personal message of the user, which is included in the notification
+    /// active: Whether the contact confirmed to receive notifications, only active contacts are notified
+    #[derive(Serialize, PartialEq, Debug, Clone)]
+    pub struct Contact {
+        pub id: u32,
+        pub user_id: String,
+        pub name: String,
+        pub email: String,
+        pub message: String,
+        pub active: bool,
+    }
+
+    /// A device of a user running the beacon client, as it's displayed in the database.
+    /// Every device has its own token, which is accepted for heartbeats of the user.
+    /// id: The id of the device, used to revoke it
+    /// user_id: The id of the user the device belongs to
+    /// name: A name the user gave the device, like "Phone"
+    /// created: A timestamp indicating when the device was registered
+    /// last_seen: A timestamp of the last request the device made, None if it never made one
+    #[derive(Serialize, PartialEq, Debug, Clone)]
+    pub struct Device {
+        pub id: u32,
+        pub user_id: String,
+        pub name: String,
+        pub created: u32,
+        pub last_seen: Option<u32>,
+    }
+    /// The credentials of a device, these are only handed out when the device is registered
+    /// device: The device the token belongs to
+    /// token: The secret token of the device (`Auth-Token` header), the server only keeps its hash
+    #[derive(Serialize, PartialEq, Debug, Clone)]
+    pub struct DeviceCredentials {
+        pub device: Device,
+        pub token: String,
+    }
+
+    /// The Alarm object, as it's displayed in the database.
+    /// id: The id of the user the alarm was raised for
+    /// ack_code: A random code used to acknowledge the alarm via link
+    /// raised: A timestamp indicating when the alarm was raised
+    /// grace_until: A timestamp indicating when the contacts will be notified
+    /// warned, contacts_notified, admin_notified: Timestamps of the escalation stages, None if not reached yet
+    /// acknowledged: A timestamp indicating when the alarm was acknowledged, this stops the escalation.
+    /// Until the contacts were notified only the user checking in acknowledges it, afterwards
+    /// the link only spares the admin.
+    #[derive(PartialEq, Debug, Clone)]
+    pub struct Alarm {
+        pub id: String,
+        pub ack_code: String,
+        pub raised: u32,
+        pub grace_until: u32,
+        pub warned: Option<u32>,
+        pub contacts_notified: Option<u32>,
+        pub admin_notified: Option<u32>,
+        pub acknowledged: Option<u32>,
+    }
+
     /// The Verification object, as it's displayed in the database.
     /// email: the email address of the account
     /// code: the verification code, a number of up to 18 Digits
@@ -1,24 +1,374 @@
 pub mod sqlite_handler {
-    use crate::data::data_forms::{User, Verification};
+    use crate::audit::audit_log::{self, AuditKind, AuditRecord};
+    use crate::data::data_forms::{
+        Alarm, AuditEntries, AuditQuery, BeaconPolicy, Contact, Device, StateChange,
+        TransitionCause, TransitionError, User, UserSettings, UserState, Verification,
+    };
+    use crate::signature_handler::signing::{self, ServerKey};
     use chrono::Utc;
+    use r2d2_sqlite::SqliteConnectionManager;
     use rand::{distributions::Alphanumeric, Rng};
-    use rusqlite::{self, Connection};
-    use std::convert::TryFrom;
+    use rusqlite::{
+        self, ffi,
+        types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Type, ValueRef},
+        Connection, OptionalExtension, ToSql, Transaction, TransactionBehavior,
+    };
+    use std::{collections::HashMap, convert::TryFrom, ops::Deref, time::Duration};
+
+    /// A pool of connections to the database shared by the request handlers
+    pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+
+    /// How long a connection waits for a lock held by another connection before failing
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+    /// The length of the public user ids
+    const USER_ID_LENGTH: usize = 32;
+    /// The length of the secret API tokens
+    const TOKEN_LENGTH: usize = 64;
+    /// The number of leading characters of a token stored in plain text to find its user
+    const TOKEN_PREFIX_LENGTH: usize = 8;
+
+    /// Returns a random alphanumeric string of the given length
+    fn random_string(length: usize) -> String {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(length)
+            .map(char::from)
+            .collect()
+    }
+    /// Returns the prefix, a random salt and the salted hash of the given token,
+    /// as they are stored in the users table
+    fn hash_new_token(token: &str) -> (String, String, String) {
+        let salt = random_string(32);
+        let hash = signing::hash_token(token, &salt);
+        (token_prefix(token), salt, hash)
+    }
+    /// Returns the part of the token which is stored in plain text
+    fn token_prefix(token: &str) -> String {
+        token.chars().take(TOKEN_PREFIX_LENGTH).collect()
+    }
+
+    /// The table the users are stored in, see `purge_archived_audit`
+    const USERS_TABLE: &str = "users";
+    /// The table every state change of a user is recorded in, see `update_state_user`
+    const STATE_HISTORY_TABLE: &str = "state_history";
+    /// The table the beacon policy and audit retention of a user are read from,
+    /// see `get_deadline` and `compact_audit`
+    const SETTINGS_TABLE: &str = "settings";
+    /// The table the audit logs of all users are kept in, see `append_audit`
+    const AUDIT_TABLE: &str = "audit";
+    /// The table the imported audit log files are recorded in, see `import_audit_entries`
+    const AUDIT_IMPORTS_TABLE: &str = "audit_imports";
+    /// The table the trusted contacts of all users are kept in, see `delete_account`
+    const CONTACTS_TABLE: &str = "contacts";
+    /// The table the pending confirmations of contacts are kept in, see `delete_account`
+    const CONTACT_VERIFICATION_TABLE: &str = "contact_verification";
+    /// The tables keyed by the id of the user, see `delete_account`
+    const ACCOUNT_ID_TABLES: [&str; 3] = [SETTINGS_TABLE, "deadlines", "alarms"];
+    /// The tables with a user_id column, see `delete_account`
+    const ACCOUNT_USER_ID_TABLES: [&str; 5] = [
+        CONTACTS_TABLE,
+        STATE_HISTORY_TABLE,
+        "devices",
+        AUDIT_TABLE,
+        "email_verification",
+    ];
+    /// The tables of verification codes sent to the email of the user, see `delete_account`
+    const ACCOUNT_VERIFICATION_TABLES: [&str; 2] = ["verification", "revival_verification"];
+
+    // The user state is stored by its number, see `UserState`
+    impl ToSql for UserState {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            Ok(ToSqlOutput::from(self.to_i8()))
+        }
+    }
+    impl FromSql for UserState {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            let number = value.as_i64()?;
+            i8::try_from(number)
+                .ok()
+                .and_then(UserState::from_i8)
+                .ok_or(FromSqlError::OutOfRange(number))
+        }
+    }
+
+    // The cause of a state change is stored by its name, see `TransitionCause`
+    impl ToSql for TransitionCause {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            Ok(ToSqlOutput::from(self.as_str()))
+        }
+    }
+    impl FromSql for TransitionCause {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            TransitionCause::from_name(value.as_str()?).ok_or(FromSqlError::InvalidType)
+        }
+    }
+
+    // The beacon policy is stored by its name, see `BeaconPolicy`
+    impl ToSql for BeaconPolicy {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            Ok(ToSqlOutput::from(self.as_str()))
+        }
+    }
+    impl FromSql for BeaconPolicy {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            BeaconPolicy::from_name(value.as_str()?).ok_or(FromSqlError::InvalidType)
+        }
+    }
+
+    // The kind of an audit entry is stored by its name, see `AuditKind`
+    impl ToSql for AuditKind {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            Ok(ToSqlOutput::from(self.as_str()))
+        }
+    }
+    impl FromSql for AuditKind {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            AuditKind::from_name(value.as_str()?).ok_or(FromSqlError::InvalidType)
+        }
+    }
+
+    /// Configures a freshly opened connection: WAL mode lets readers and the writer work
+    /// concurrently, the busy timeout makes concurrent writers wait instead of failing at once
+    fn configure_connection(connection: &Connection) -> std::result::Result<(), rusqlite::Error> {
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.execute_batch("PRAGMA journal_mode = WAL;")
+    }
+
+    /// Creates the connection pool for the database at the given path
+    pub fn create_pool(db_path: &str) -> std::result::Result<DbPool, r2d2::Error> {
+        let manager = SqliteConnectionManager::file(db_path).with_init(|c| configure_connection(c));
+        r2d2::Pool::builder()
+            .connection_timeout(BUSY_TIMEOUT)
+            .build(manager)
+    }
+
+    /// A schema migration, either plain SQL or a function for changes SQL can't express
+    enum Migration {
+        Sql(&'static str),
+        Code(fn(&Transaction) -> std::result::Result<(), rusqlite::Error>),
+    }
+
+    /// The schema migrations, applied in order and each exactly once at startup.
+    /// After the migration at index n the database has schema version n + 1.
+    /// Released migrations must never change, changes to the schema are added as a new one.
+    const MIGRATIONS: &[Migration] = &[
+        // 1: The schema as it was created before migrations were introduced
+        Migration::Sql("CREATE TABLE IF NOT EXISTS users ('id' TEXT, 'email' TEXT, 'state' INTEGER, PRIMARY KEY('id'));
+        CREATE TABLE IF NOT EXISTS state_history ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'old_state' INTEGER, 'new_state' INTEGER, 'cause' TEXT, 'timestamp' INTEGER);
+        CREATE TABLE IF NOT EXISTS verification ('email' TEXT, 'code' INTEGER, 'expires' INTEGER);
+        CREATE TABLE IF NOT EXISTS contact_verification ('email' TEXT, 'code' INTEGER, 'expires' INTEGER);
+        CREATE TABLE IF NOT EXISTS revival_verification ('email' TEXT, 'code' INTEGER, 'expires' INTEGER);
+        CREATE TABLE IF NOT EXISTS deadlines ('id' TEXT, 'expires' INTEGER, PRIMARY KEY('id'));
+        CREATE TABLE IF NOT EXISTS settings ('id' TEXT, 'interval' INTEGER, PRIMARY KEY('id'));
+        CREATE TABLE IF NOT EXISTS alarms ('id' TEXT, 'ack_code' TEXT, 'raised' INTEGER, 'grace_until' INTEGER, 'warned' INTEGER, 'contacts_notified' INTEGER, 'admin_notified' INTEGER, 'acknowledged' INTEGER, PRIMARY KEY('id'));
+        CREATE TABLE IF NOT EXISTS contacts ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'name' TEXT, 'email' TEXT, 'message' TEXT, 'active' INTEGER);"),
+        // 2: Separate the public user id from the secret token
+        Migration::Code(separate_tokens),
+        // 3: Keep the previous token of a user valid for a while after it was rotated
+        Migration::Sql("ALTER TABLE users ADD COLUMN 'prev_token_prefix' TEXT;
+        ALTER TABLE users ADD COLUMN 'prev_token_salt' TEXT;
+        ALTER TABLE users ADD COLUMN 'prev_token_hash' TEXT;
+        ALTER TABLE users ADD COLUMN 'prev_token_expires' INTEGER;
+        CREATE INDEX IF NOT EXISTS users_prev_token_prefix ON users (prev_token_prefix);"),
+        // 4: Devices with their own tokens
+        Migration::Sql("CREATE TABLE IF NOT EXISTS devices ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'name' TEXT, 'token_prefix' TEXT, 'token_salt' TEXT, 'token_hash' TEXT, 'created' INTEGER, 'last_seen' INTEGER);
+        CREATE INDEX IF NOT EXISTS devices_token_prefix ON devices (token_prefix);"),
+        // 5: A deadline per beacon of a user and the policy combining them
+        Migration::Sql("ALTER TABLE deadlines RENAME TO deadlines_single;
+        CREATE TABLE deadlines ('id' TEXT, 'beacon' TEXT, 'expires' INTEGER, PRIMARY KEY('id', 'beacon'));
+        INSERT INTO deadlines (id, beacon, expires) SELECT id, 'default', expires FROM deadlines_single;
+        DROP TABLE deadlines_single;
+        ALTER TABLE settings ADD COLUMN 'policy' TEXT NOT NULL DEFAULT 'any';"),
+        // 6: Count the failed attempts to submit a verification code
+        Migration::Sql("ALTER TABLE verification ADD COLUMN 'attempts' INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE contact_verification ADD COLUMN 'attempts' INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE revival_verification ADD COLUMN 'attempts' INTEGER NOT NULL DEFAULT 0;"),
+        // 7: The audit logs, which were kept in a file per user before
+        Migration::Sql("CREATE TABLE IF NOT EXISTS audit ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'time' INTEGER, 'kind' TEXT, 'record' TEXT, 'hash' TEXT);
+        CREATE INDEX IF NOT EXISTS audit_user_time ON audit (user_id, time);"),
+        // 8: The audit retention a user chose instead of the retention of the server
+        Migration::Sql("ALTER TABLE settings ADD COLUMN 'audit_entries' INTEGER;
+        ALTER TABLE settings ADD COLUMN 'audit_max_age' INTEGER;"),
+        // 9: Revived users (2) are active (0) again, the revival is only kept in the history
+        Migration::Sql("UPDATE users SET state = 0 WHERE state = 2;
+        UPDATE state_history SET old_state = 0 WHERE old_state = 2;
+        UPDATE state_history SET new_state = 0 WHERE new_state = 2;"),
+        // 10: The alarm engine looks for deceased users without reading every user
+        Migration::Sql("CREATE INDEX IF NOT EXISTS users_state ON users (state);"),
+        // 11: Codes confirming the new email of a user before it's changed
+        Migration::Sql("CREATE TABLE IF NOT EXISTS email_verification ('email' TEXT, 'code' INTEGER, 'expires' INTEGER, 'attempts' INTEGER NOT NULL DEFAULT 0, 'user_id' TEXT);"),
+        // 12: The audit log files which were imported, so none is imported twice
+        Migration::Sql("CREATE TABLE IF NOT EXISTS audit_imports ('file' TEXT, 'time' INTEGER, PRIMARY KEY('file'));"),
+    ];
+
+    /// Until now the id of a user was its token as well. Every user gets a new public id and
+    /// the old id is kept as the token, of which only a salted hash is stored.
+    fn separate_tokens(transaction: &Transaction) -> std::result::Result<(), rusqlite::Error> {
+        transaction.execute_batch(
+            "ALTER TABLE users ADD COLUMN 'token_prefix' TEXT;
+            ALTER TABLE users ADD COLUMN 'token_salt' TEXT;
+            ALTER TABLE users ADD COLUMN 'token_hash' TEXT;
+            CREATE INDEX IF NOT EXISTS users_token_prefix ON users (token_prefix);",
+        )?;
+        let tokens: Vec<String> = transaction
+            .prepare("SELECT id FROM users")?
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        for token in tokens {
+            let id = random_string(USER_ID_LENGTH);
+            let (prefix, salt, hash) = hash_new_token(&token);
+            transaction.execute(
+                "UPDATE users SET id = (?), token_prefix = (?), token_salt = (?), token_hash = (?) WHERE id = (?)",
+                rusqlite::params![id, prefix, salt, hash, token],
+            )?;
+            for table in ["settings", "deadlines", "alarms"] {
+                transaction.execute(
+                    &format!("UPDATE {} SET id = (?) WHERE id = (?)", table),
+                    [&id, &token],
+                )?;
+            }
+            for table in ["contacts", "state_history"] {
+                transaction.execute(
+                    &format!("UPDATE {} SET user_id = (?) WHERE user_id = (?)", table),
+                    [&id, &token],
+                )?;
+            }
+        }
+        log::info!("Separated the ids and tokens of all users");
+        Ok(())
+    }
+
+    /// Applies all migrations the database has not seen yet, the applied versions are tracked
+    /// in the schema_version table. Returns the schema version of the database afterwards.
+    /// Fails if the database was already migrated by a newer version of this server.
+    pub fn migrate(connection: &mut Connection) -> std::result::Result<u32, rusqlite::Error> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS schema_version ('version' INTEGER, 'applied' INTEGER)",
+            [],
+        )?;
+        let mut version: u32 = connection.query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+            [],
+            |row| row.get(0),
+        )?;
+        if version as usize > MIGRATIONS.len() {
+            return Err(rusqlite::Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISMATCH),
+                Some(format!(
+                    "Database schema version {} is newer than the supported version {}",
+                    version,
+                    MIGRATIONS.len()
+                )),
+            ));
+        }
+        for migration in MIGRATIONS.iter().skip(version as usize) {
+            version += 1;
+            let transaction = connection.transaction()?;
+            match migration {
+                Migration::Sql(sql) => transaction.execute_batch(sql)?,
+                Migration::Code(function) => function(&transaction)?,
+            }
+            transaction.execute(
+                "INSERT INTO schema_version (version, applied) VALUES ((?), (?))",
+                rusqlite::params![version, Utc::now().timestamp()],
+            )?;
+            transaction.commit()?;
+            log::info!("Migrated the database to schema version {}", version);
+        }
+        Ok(version)
+    }
+    /// Opens the database at the given path and migrates it, see `migrate`
+    pub fn migrate_database(db_path: &str) -> std::result::Result<u32, rusqlite::Error> {
+        let mut connection = Connection::open(db_path)?;
+        configure_connection(&connection)?;
+        migrate(&mut connection)
+    }
+
+    /// Returns the hash the next entry is chained to and the kind of the last entry in the audit
+    /// log of the given user, None if the log is empty. For an anchor that's the hash it names.
+    fn audit_head(
+        connection: &Connection,
+        user_id: &str,
+    ) -> std::result::Result<Option<(String, AuditKind)>, rusqlite::Error> {
+        connection
+            .query_row(
+                &format!(
+                    "SELECT hash, kind FROM {} WHERE user_id = (?) ORDER BY id DESC LIMIT 1",
+                    AUDIT_TABLE
+                ),
+                [user_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+    /// Chains the given entry to the given hash and appends it to the audit log of the given
+    /// user. Returns the hash of the entry, which the next entry is chained to.
+    /// Appends the given entries to the audit log of the given user within the given
+    /// transaction, each chained to the hash of the entry before it
+    fn append_audit_chain(
+        connection: &Connection,
+        user_id: &str,
+        records: Vec<AuditRecord>,
+    ) -> std::result::Result<(), rusqlite::Error> {
+        let mut prev = match audit_head(connection, user_id)? {
+            Some((hash, _)) => hash,
+            None => audit_log::genesis(user_id),
+        };
+        for record in records {
+            prev = insert_audit(connection, user_id, prev, record)?;
+        }
+        Ok(())
+    }
+    fn insert_audit(
+        connection: &Connection,
+        user_id: &str,
+        prev: String,
+        mut record: AuditRecord,
+    ) -> std::result::Result<String, rusqlite::Error> {
+        record.prev = Some(prev);
+        let line = serde_json::to_string(&record)
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        let hash = signing::hash(&line);
+        connection.execute(
+            &format!(
+                "INSERT INTO {} (user_id, time, kind, record, hash) VALUES ((?), (?), (?), (?), (?))",
+                AUDIT_TABLE
+            ),
+            rusqlite::params![user_id, record.time, record.kind, line, hash],
+        )?;
+        Ok(hash)
+    }
+    /// Parses an entry of the audit log as it's stored
+    fn parse_audit_record(line: &str) -> std::result::Result<AuditRecord, rusqlite::Error> {
+        serde_json::from_str(line)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(err)))
+    }
+
+    /// A connection either owned by a database state or borrowed from the pool
+    #[derive(Debug)]
+    enum DatabaseConnection {
+        Owned(Connection),
+        Pooled(r2d2::PooledConnection<SqliteConnectionManager>),
+    }
+    impl Deref for DatabaseConnection {
+        type Target = Connection;
+        fn deref(&self) -> &Connection {
+            match self {
+                DatabaseConnection::Owned(connection) => connection,
+                DatabaseConnection::Pooled(connection) => connection,
+            }
+        }
+    }
 
     #[derive(Debug)]
     pub struct DatabaseState {
-        connection: Connection,
+        connection: DatabaseConnection,
         table_name: String,
     }
     impl DatabaseState {
         /// Initialize a new database state
         pub fn init(db_path: String) -> std::result::Result<Self, rusqlite::Error> {
-            let table_name = "users".to_string();
-            let connection = Connection::open(db_path)?;
-            Ok(Self {
-                table_name,
-                connection,
-            })
+            Self::init_with_table_name(db_path, "users".to_string())
         }
         /// Initialize a new database state with a given table name
         pub fn init_with_table_name(
@@ -26,13 +376,35 @@ pub mod sqlite_handler {
             table_name: String,
         ) -> std::result::Result<Self, rusqlite::Error> {
             let connection = Connection::open(db_path)?;
+            configure_connection(&connection)?;
             Ok(Self {
                 table_name,
-                connection,
+                connection: DatabaseConnection::Owned(connection),
+            })
+        }
+        /// Initialize a new database state for the given table with a connection from the pool.
+        /// If no connection becomes available in time, this fails like opening the database would.
+        pub fn from_pool(
+            pool: &DbPool,
+            table_name: &str,
+        ) -> std::result::Result<Self, rusqlite::Error> {
+            let connection = pool.get().map_err(|e| {
+                rusqlite::Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_CANTOPEN),
+                    Some(e.to_string()),
+                )
+            })?;
+            Ok(Self {
+                table_name: table_name.to_string(),
+                connection: DatabaseConnection::Pooled(connection),
             })
         }
+        /// Closes the connection, pooled connections are returned to the pool instead
         pub fn kill(self) -> std::result::Result<(), rusqlite::Error> {
-            let mut conn = self.connection;
+            let mut conn = match self.connection {
+                DatabaseConnection::Owned(connection) => connection,
+                DatabaseConnection::Pooled(_) => return Ok(()),
+            };
             let mut err = rusqlite::Error::InvalidQuery;
             for _ in 0..5 {
                 let r = conn.close();
@@ -51,7 +423,7 @@ pub mod sqlite_handler {
         pub fn create_table_for_user(&self) -> std::result::Result<(), rusqlite::Error> {
             self.connection.execute(
                     &format!(
-                        "CREATE TABLE IF NOT EXISTS {} ('id' TEXT, 'email' TEXT, 'state' INTEGER, PRIMARY KEY('id'))",
+                        "CREATE TABLE IF NOT EXISTS {} ('id' TEXT, 'email' TEXT, 'state' INTEGER, 'token_prefix' TEXT, 'token_salt' TEXT, 'token_hash' TEXT, 'prev_token_prefix' TEXT, 'prev_token_salt' TEXT, 'prev_token_hash' TEXT, 'prev_token_expires' INTEGER, PRIMARY KEY('id'))",
                         self.table_name
                     ),
                     []
@@ -62,7 +434,93 @@ pub mod sqlite_handler {
         pub fn create_table_for_verification(&self) -> std::result::Result<(), rusqlite::Error> {
             self.connection.execute(
                 &format!(
-                    "CREATE TABLE IF NOT EXISTS {} ('email' TEXT, 'code' INTEGER, 'expires' INTEGER)",
+                    "CREATE TABLE IF NOT EXISTS {} ('email' TEXT, 'code' INTEGER, 'expires' INTEGER, 'attempts' INTEGER NOT NULL DEFAULT 0)",
+                    self.table_name
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+        /// Create a new table for the check-in deadlines of users, if not already present
+        pub fn create_table_for_deadline(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ('id' TEXT, 'beacon' TEXT, 'expires' INTEGER, PRIMARY KEY('id', 'beacon'))",
+                    self.table_name
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+        /// Create a new table for the UserSettings struct, if not already present
+        pub fn create_table_for_settings(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ('id' TEXT, 'interval' INTEGER, 'policy' TEXT NOT NULL DEFAULT 'any', 'audit_entries' INTEGER, 'audit_max_age' INTEGER, PRIMARY KEY('id'))",
+                    self.table_name
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+        /// Create a new table for the Alarm struct, if not already present
+        pub fn create_table_for_alarm(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ('id' TEXT, 'ack_code' TEXT, 'raised' INTEGER, 'grace_until' INTEGER, 'warned' INTEGER, 'contacts_notified' INTEGER, 'admin_notified' INTEGER, 'acknowledged' INTEGER, PRIMARY KEY('id'))",
+                    self.table_name
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+        /// Create a new table for the Contact struct, if not already present
+        pub fn create_table_for_contact(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'name' TEXT, 'email' TEXT, 'message' TEXT, 'active' INTEGER)",
+                    self.table_name
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+        /// Create a Table for the Device struct if not yet existent
+        pub fn create_table_for_device(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'name' TEXT, 'token_prefix' TEXT, 'token_salt' TEXT, 'token_hash' TEXT, 'created' INTEGER, 'last_seen' INTEGER)",
+                    self.table_name
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+        /// Create a Table for the state history if not yet existent
+        pub fn create_table_for_state_history(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'old_state' INTEGER, 'new_state' INTEGER, 'cause' TEXT, 'timestamp' INTEGER)",
+                    self.table_name
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+        /// Create a Table for the audit logs if not yet existent
+        pub fn create_table_for_audit(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {0} ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'time' INTEGER, 'kind' TEXT, 'record' TEXT, 'hash' TEXT);
+                CREATE INDEX IF NOT EXISTS {0}_user_time ON {0} (user_id, time);",
+                self.table_name
+            ))?;
+            Ok(())
+        }
+        /// Create a new table for the imported audit log files, if not already present
+        pub fn create_table_for_audit_imports(&self) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ('file' TEXT, 'time' INTEGER, PRIMARY KEY('file'))",
                     self.table_name
                 ),
                 [],
@@ -121,17 +579,129 @@ pub mod sqlite_handler {
                 None => Ok(None),
             }
         }
-        /// Generates a new User-id and adds it with the given email to the database, then returns the full user
-        /// The ID is generated randomly and regenerated if it already existed. If there are a lot of ids already,
+        /// Retrieves the User the given token belongs to, returns None if the token is invalid.
+        /// The previous token of a user is accepted as well until its overlap window ends.
+        /// Only a prefix of the token is used to look the user up, the full token is compared
+        /// against the salted hash in constant time.
+        pub fn get_user_by_token(
+            &self,
+            token: &str,
+        ) -> std::result::Result<Option<User>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT id, email, state, token_salt, token_hash, prev_token_salt, \
+                CASE WHEN prev_token_expires > (?2) THEN prev_token_hash END FROM {} \
+                WHERE token_prefix = (?1) OR (prev_token_prefix = (?1) AND prev_token_expires > (?2))",
+                self.table_name
+            ))?;
+            let candidates = q.query_map(
+                rusqlite::params![token_prefix(token), Utc::now().timestamp()],
+                |row| {
+                    Ok((
+                        User {
+                            id: row.get(0)?,
+                            email: row.get(1)?,
+                            state: row.get(2)?,
+                        },
+                        [
+                            (
+                                row.get::<_, Option<String>>(3)?,
+                                row.get::<_, Option<String>>(4)?,
+                            ),
+                            (
+                                row.get::<_, Option<String>>(5)?,
+                                row.get::<_, Option<String>>(6)?,
+                            ),
+                        ],
+                    ))
+                },
+            )?;
+            for candidate in candidates {
+                let (user, hashes) = candidate?;
+                // The hash of an expired previous token is not selected at all
+                for (salt, hash) in hashes.iter() {
+                    if let (Some(salt), Some(hash)) = (salt, hash) {
+                        if signing::verify_token(token, salt, hash) {
+                            return Ok(Some(user));
+                        }
+                    }
+                }
+            }
+            Ok(None)
+        }
+        /// Replaces the token of the given user, only a salted hash of the token is stored.
+        /// All other tokens of the user become invalid immediately.
+        pub fn set_user_token(
+            &self,
+            id: &str,
+            token: &str,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            let (prefix, salt, hash) = hash_new_token(token);
+            self.connection.execute(
+                &format!(
+                    "UPDATE {} SET token_prefix = (?), token_salt = (?), token_hash = (?), \
+                    prev_token_prefix = NULL, prev_token_salt = NULL, prev_token_hash = NULL, prev_token_expires = NULL \
+                    WHERE id = (?)",
+                    self.table_name
+                ),
+                [&prefix, &salt, &hash, id],
+            )?;
+            Ok(())
+        }
+        /// Replaces the token of the given user, the current token stays valid until the given
+        /// timestamp. A token which was still valid from an earlier rotation becomes invalid.
+        pub fn rotate_user_token(
+            &self,
+            id: &str,
+            token: &str,
+            overlap_until: u32,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            let (prefix, salt, hash) = hash_new_token(token);
+            self.connection.execute(
+                &format!(
+                    "UPDATE {} SET prev_token_prefix = token_prefix, prev_token_salt = token_salt, \
+                    prev_token_hash = token_hash, prev_token_expires = (?), \
+                    token_prefix = (?), token_salt = (?), token_hash = (?) WHERE id = (?)",
+                    self.table_name
+                ),
+                rusqlite::params![overlap_until, prefix, salt, hash, id],
+            )?;
+            Ok(())
+        }
+        /// Invalidates all tokens of the given user, a new one is only issued by verifying
+        /// the email of the user again.
+        pub fn revoke_user_tokens(&self, id: &str) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "UPDATE {} SET token_prefix = NULL, token_salt = NULL, token_hash = NULL, \
+                    prev_token_prefix = NULL, prev_token_salt = NULL, prev_token_hash = NULL, prev_token_expires = NULL \
+                    WHERE id = (?)",
+                    self.table_name
+                ),
+                [id],
+            )?;
+            Ok(())
+        }
+        /// Whether the given user has a valid token, which is not the case after a revocation
+        pub fn has_token(&self, id: &str) -> std::result::Result<bool, rusqlite::Error> {
+            self.connection.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM {} WHERE id = (?) AND token_hash IS NOT NULL",
+                    self.table_name
+                ),
+                [id],
+                |row| row.get::<_, u32>(0).map(|count| count > 0),
+            )
+        }
+        /// Generates a new random token
+        pub fn generate_token() -> String {
+            random_string(TOKEN_LENGTH)
+        }
+        /// Generates a new random User-id which is not yet present in the table.
+        /// The ID is regenerated if it already existed. If there are a lot of ids already,
         /// the process will take longer, note that this function has no timeout by itself.
-        pub fn new_user(&self, email: &String) -> std::result::Result<User, rusqlite::Error> {
-            log::debug!("Creating New User...");
-            let new_id = loop {
-                let gen_id: String = rand::thread_rng()
-                    .sample_iter(&Alphanumeric)
-                    .take(64)
-                    .map(char::from)
-                    .collect();
+        pub fn generate_user_id(&self) -> std::result::Result<String, rusqlite::Error> {
+            loop {
+                let gen_id = random_string(USER_ID_LENGTH);
 
                 let mut check_id = self.connection.prepare(&format!(
                     "SELECT * FROM {} WHERE id = '{}'",
@@ -144,36 +714,69 @@ pub mod sqlite_handler {
                         state: row.get(2)?,
                     })
                 })?;
-                if match results.next() {
-                    Some(_) => true,
-                    None => false,
-                } {
+                if results.next().is_some() {
                     drop(results);
                     log::debug!("Generated ID already exists!, generating new one...");
                     check_id.finalize()?;
                     continue;
                 } else {
                     log::debug!("Generated new ID successfully");
-                    break gen_id;
+                    return Ok(gen_id);
                 }
-            };
+            }
+        }
+        /// Generates a new User-id and token and adds them with the given email to the database,
+        /// then returns the full user along with the token.
+        /// See `generate_user_id` for how the id is generated.
+        pub fn new_user(
+            &self,
+            email: &String,
+        ) -> std::result::Result<(User, String), rusqlite::Error> {
+            log::debug!("Creating New User...");
+            let new_id = self.generate_user_id()?;
+            let token = Self::generate_token();
+            let (prefix, salt, hash) = hash_new_token(&token);
             log::debug!("Writing changes to Database...");
-            self.connection.execute(
+            let transaction = self.connection.unchecked_transaction()?;
+            transaction.execute(
                 &format!(
-                    "INSERT INTO {} (id, email, state) VALUES ((?), (?), (?))",
+                    "INSERT INTO {} (id, email, state, token_prefix, token_salt, token_hash) VALUES ((?), (?), (?), (?), (?), (?))",
                     self.table_name
                 ),
-                [new_id.clone(), email.clone(), 0.to_string()],
+                rusqlite::params![new_id, email, UserState::Active, prefix, salt, hash],
+            )?;
+            self.add_state_change(
+                &new_id,
+                UserState::Unknown,
+                UserState::Active,
+                TransitionCause::Registration,
             )?;
+            transaction.commit()?;
 
             log::debug!("Created a new User successfully");
-            Ok(User {
-                id: new_id,
-                email: email.to_string(),
-                state: 0,
-            })
+            Ok((
+                User {
+                    id: new_id,
+                    email: email.to_string(),
+                    state: UserState::Active,
+                },
+                token,
+            ))
+        }
+        /// Update the email of an existing user by its ID.
+        pub fn update_email_user(
+            &self,
+            id: &str,
+            email: &str,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!("UPDATE {} SET email = (?) WHERE id = (?)", self.table_name),
+                [email, id],
+            )?;
+            Ok(())
         }
         /// Deletes the given user from the database by it's id.
+        /// This works on every table with an id column (users, settings, deadlines).
         pub fn delete_user(&self, user_id: &String) -> std::result::Result<(), rusqlite::Error> {
             self.connection.execute(
                 &format!("DELETE FROM {} WHERE id = (?)", self.table_name),
@@ -181,29 +784,136 @@ pub mod sqlite_handler {
             )?;
             Ok(())
         }
+        /// Deletes the given user with all of their data: settings, deadlines, alarm, contacts,
+        /// state history, devices, audit log and pending verification codes, including the
+        /// confirmations sent to their contacts. Either everything is deleted or nothing.
+        /// This has to be called on the users table.
+        pub fn delete_account(
+            &self,
+            user_id: &str,
+            email: &str,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            let transaction =
+                Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+            // Confirmations are keyed by the address of the contact, which may be
+            // awaiting the confirmation of another user's contact as well
+            transaction.execute(
+                &format!(
+                    "DELETE FROM {0} WHERE email IN (SELECT email FROM {1} WHERE user_id = (?1)) \
+                    AND email NOT IN (SELECT email FROM {1} WHERE user_id != (?1) AND active = 0)",
+                    CONTACT_VERIFICATION_TABLE, CONTACTS_TABLE
+                ),
+                [user_id],
+            )?;
+            for table in ACCOUNT_ID_TABLES {
+                transaction.execute(&format!("DELETE FROM {} WHERE id = (?)", table), [user_id])?;
+            }
+            for table in ACCOUNT_USER_ID_TABLES {
+                transaction.execute(
+                    &format!("DELETE FROM {} WHERE user_id = (?)", table),
+                    [user_id],
+                )?;
+            }
+            for table in ACCOUNT_VERIFICATION_TABLES {
+                transaction
+                    .execute(&format!("DELETE FROM {} WHERE email = (?)", table), [email])?;
+            }
+            transaction.execute(
+                &format!("DELETE FROM {} WHERE id = (?)", self.table_name),
+                [user_id],
+            )?;
+            transaction.commit()
+        }
         /// Update the state of an existing user by its ID.
-        /// *This won't do anything if the user is already deceased!!*
-        /// Returns true if updated successfully, passes errors and returns
-        /// false if not updated (as user is already deceased)
+        /// The change is checked against the transition table of `UserState`, the outer result
+        /// contains database errors, the inner one rejected transitions (a non-existent user is
+        /// treated as `Unknown` and can't change its state at all).
+        /// Every actual change is recorded with the given cause in the state history.
+        /// Returns the new state if updated successfully.
         pub fn update_state_user(
             &self,
-            id: &String,
-            new_state: i8,
-        ) -> std::result::Result<bool, rusqlite::Error> {
-            if self.get_user_by_id(id)?.unwrap_or(User::empty()).state < 10 {
-                self.connection.execute(
-                    &format!("UPDATE {} SET state = (?) WHERE id = (?)", self.table_name),
-                    [new_state.to_string(), id.to_string()],
-                )?;
-                Ok(true)
-            } else {
-                Ok(false)
+            id: &str,
+            new_state: UserState,
+            cause: TransitionCause,
+        ) -> std::result::Result<std::result::Result<UserState, TransitionError>, rusqlite::Error>
+        {
+            let current = match self.get_user_by_id(&id.to_string())? {
+                Some(user) => user.state,
+                None => UserState::Unknown,
+            };
+            if let Err(e) = current.transition(new_state, cause) {
+                return Ok(Err(e));
+            }
+            // Repeating the current state (e.g. a heartbeat) is no change worth recording
+            if current == new_state {
+                return Ok(Ok(new_state));
+            }
+            let transaction = self.connection.unchecked_transaction()?;
+            // Only change the state if nobody else changed it in the meantime
+            let changed = transaction.execute(
+                &format!(
+                    "UPDATE {} SET state = (?) WHERE id = (?) AND state = (?)",
+                    self.table_name
+                ),
+                rusqlite::params![new_state, id, current],
+            )?;
+            if changed == 0 {
+                drop(transaction);
+                return self.update_state_user(id, new_state, cause);
             }
+            self.add_state_change(id, current, new_state, cause)?;
+            transaction.commit()?;
+            Ok(Ok(new_state))
+        }
+        /// Records a state change of the given user in the state history
+        fn add_state_change(
+            &self,
+            user_id: &str,
+            old_state: UserState,
+            new_state: UserState,
+            cause: TransitionCause,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "INSERT INTO {} (user_id, old_state, new_state, cause, timestamp) VALUES ((?), (?), (?), (?), (?))",
+                    STATE_HISTORY_TABLE
+                ),
+                rusqlite::params![
+                    user_id,
+                    old_state,
+                    new_state,
+                    cause,
+                    Utc::now().timestamp()
+                ],
+            )?;
+            Ok(())
+        }
+        /// Select the state history of the given user, oldest change first.
+        /// This has to be called on the state history table.
+        pub fn get_state_history(
+            &self,
+            user_id: &str,
+        ) -> std::result::Result<Vec<StateChange>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT user_id, old_state, new_state, cause, timestamp FROM {} WHERE user_id = (?) ORDER BY id",
+                self.table_name
+            ))?;
+            let results = q.query_map([user_id], |row| {
+                Ok(StateChange {
+                    user_id: row.get(0)?,
+                    old_state: row.get(1)?,
+                    new_state: row.get(2)?,
+                    cause: row.get(3)?,
+                    timestamp: row.get(4)?,
+                })
+            })?;
+
+            results.collect()
         }
         /// Select all users by a given state
         pub fn get_user_by_state(
             &self,
-            state: i8,
+            state: UserState,
         ) -> std::result::Result<Vec<User>, rusqlite::Error> {
             let mut q = self.connection.prepare(&format!(
                 "SELECT * FROM {} WHERE state = (?)",
@@ -227,6 +937,16 @@ pub mod sqlite_handler {
             &self,
             email: String,
             duplicate_check: bool,
+        ) -> std::result::Result<Option<Verification>, rusqlite::Error> {
+            self.generate_verification_code_with_lifetime(email, duplicate_check, 600)
+        }
+        /// Same as `generate_verification_code` but the code expires after the given
+        /// amount of seconds.
+        pub fn generate_verification_code_with_lifetime(
+            &self,
+            email: String,
+            duplicate_check: bool,
+            lifetime: u32,
         ) -> std::result::Result<Option<Verification>, rusqlite::Error> {
             if duplicate_check {
                 // Do nothing if an email is found
@@ -262,7 +982,7 @@ pub mod sqlite_handler {
             };
 
             let expires: u32 =
-                u32::try_from(Utc::now().timestamp()).expect("Time went backwards") + 600;
+                u32::try_from(Utc::now().timestamp()).expect("Time went backwards") + lifetime;
             let r = Verification {
                 email,
                 code,
@@ -279,13 +999,55 @@ pub mod sqlite_handler {
             log::debug!("Created a new verification entry successfully");
             Ok(Some(r))
         }
-        /// Select a verification entry by a given email
-        pub fn get_verification_by_email(
+        /// Generate a new verification entry for the given user, who wants to use the given
+        /// email from now on. A code the user was sent before is replaced. Returns None if
+        /// the email is awaiting the confirmation of another user.
+        /// This has to be called on the email verification table.
+        pub fn generate_email_change_code(
             &self,
-            email: &String,
-        ) -> Result<Option<Verification>, rusqlite::Error> {
+            user_id: &str,
+            email: String,
+        ) -> std::result::Result<Option<Verification>, rusqlite::Error> {
+            self.connection.execute(
+                &format!("DELETE FROM {} WHERE user_id = (?)", self.table_name),
+                [user_id],
+            )?;
+            let verification = match self.generate_verification_code(email, true)? {
+                Some(val) => val,
+                None => return Ok(None),
+            };
+            self.connection.execute(
+                &format!(
+                    "UPDATE {} SET user_id = (?) WHERE code = (?)",
+                    self.table_name
+                ),
+                rusqlite::params![user_id, verification.code],
+            )?;
+            Ok(Some(verification))
+        }
+        /// Select the id of the user the given code was generated for, see
+        /// `generate_email_change_code`. This has to be called on the email verification table.
+        pub fn get_verification_user(
+            &self,
+            code: u64,
+        ) -> std::result::Result<Option<String>, rusqlite::Error> {
             let mut q = self.connection.prepare(&format!(
-                "SELECT email, code, expires FROM {} WHERE email = (?)",
+                "SELECT user_id FROM {} WHERE code = (?)",
+                self.table_name
+            ))?;
+            let mut results = q.query_map([code.to_string()], |row| row.get(0))?;
+            match results.next() {
+                Some(val) => Ok(Some(val?)),
+                None => Ok(None),
+            }
+        }
+        /// Select a verification entry by a given email
+        pub fn get_verification_by_email(
+            &self,
+            email: &String,
+        ) -> Result<Option<Verification>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT email, code, expires FROM {} WHERE email = (?)",
                 self.table_name
             ))?;
             let mut results = q.query_map([email], |row| {
@@ -300,10 +1062,44 @@ pub mod sqlite_handler {
                 None => Ok(None),
             }
         }
+        /// Deletes all verification entries of the given email
+        pub fn delete_verification_by_email(
+            &self,
+            email: &str,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!("DELETE FROM {} WHERE email = (?)", self.table_name),
+                [email],
+            )?;
+            Ok(())
+        }
+        /// Counts a failed attempt to submit the code of the given email. Once the given number
+        /// of attempts failed, the code is deleted. Returns true if the code was deleted.
+        pub fn count_failed_attempt(
+            &self,
+            email: &str,
+            max_attempts: u32,
+        ) -> std::result::Result<bool, rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "UPDATE {} SET attempts = attempts + 1 WHERE email = (?)",
+                    self.table_name
+                ),
+                [email],
+            )?;
+            let deleted = self.connection.execute(
+                &format!(
+                    "DELETE FROM {} WHERE email = (?) AND attempts >= (?)",
+                    self.table_name
+                ),
+                rusqlite::params![email, max_attempts],
+            )?;
+            Ok(deleted > 0)
+        }
         /// Verfies a given code. Returns None if the code is invalid. If the code is valid the
         /// Verification object is queried, removed from the database and the associated email is returned.
         pub fn verify_verification_code(
-            self,
+            &self,
             code: u64,
         ) -> Result<Option<String>, rusqlite::Error> {
             let mut q = self.connection.prepare(&format!(
@@ -323,8 +1119,6 @@ pub mod sqlite_handler {
                     < u32::try_from(Utc::now().timestamp()).expect("Time went backwards")
                 {
                     return Ok(None);
-                } else {
-                    ()
                 }
                 self.connection.execute(
                     &format!("DELETE FROM {} WHERE code = (?)", self.table_name),
@@ -332,8 +1126,642 @@ pub mod sqlite_handler {
                 )?;
                 Ok(Some(entry.email))
             } else {
-                return Ok(None);
+                Ok(None)
+            }
+        }
+        /// Sets the deadline of the given beacon of the user with the given ID,
+        /// replacing an existing one.
+        pub fn set_deadline(
+            &self,
+            id: &str,
+            beacon: &str,
+            expires: u32,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {} (id, beacon, expires) VALUES ((?), (?), (?))",
+                    self.table_name
+                ),
+                rusqlite::params![id, beacon, expires],
+            )?;
+            Ok(())
+        }
+        /// Removes the deadlines of all beacons of the user with the given ID, if present
+        pub fn delete_deadline(&self, id: &str) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!("DELETE FROM {} WHERE id = (?)", self.table_name),
+                [id],
+            )?;
+            Ok(())
+        }
+        /// Removes the deadline of the given beacon of the user with the given ID.
+        /// Returns false if the beacon had no deadline.
+        pub fn delete_beacon_deadline(
+            &self,
+            id: &str,
+            beacon: &str,
+        ) -> std::result::Result<bool, rusqlite::Error> {
+            let changed = self.connection.execute(
+                &format!(
+                    "DELETE FROM {} WHERE id = (?) AND beacon = (?)",
+                    self.table_name
+                ),
+                [id, beacon],
+            )?;
+            Ok(changed > 0)
+        }
+        /// Returns the deadlines of the users matching the given condition, the deadlines of the
+        /// beacons of a user are combined by the beacon policy of the user.
+        fn get_deadlines_where(
+            &self,
+            condition: &str,
+            params: &[&dyn rusqlite::ToSql],
+        ) -> std::result::Result<HashMap<String, u32>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT d.id, CASE WHEN s.policy = '{}' THEN MIN(d.expires) ELSE MAX(d.expires) END \
+                FROM {} d LEFT JOIN {} s ON s.id = d.id WHERE {} GROUP BY d.id",
+                BeaconPolicy::All.as_str(),
+                self.table_name,
+                SETTINGS_TABLE,
+                condition
+            ))?;
+            let results = q.query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+            results.collect()
+        }
+        /// Returns the deadlines of all users as a map from user ID to the expiration timestamp,
+        /// see `get_deadline` for how the deadlines of the beacons of a user are combined
+        pub fn get_all_deadlines(
+            &self,
+        ) -> std::result::Result<HashMap<String, u32>, rusqlite::Error> {
+            self.get_deadlines_where("1", &[])
+        }
+        /// Returns the deadline of the user with the given ID, if any beacon has one.
+        /// With the `any` policy this is the latest deadline of all beacons, with the `all`
+        /// policy the earliest one.
+        pub fn get_deadline(&self, id: &str) -> std::result::Result<Option<u32>, rusqlite::Error> {
+            Ok(self.get_deadlines_where("d.id = (?)", &[&id])?.remove(id))
+        }
+        /// Returns the deadlines of every beacon of the user with the given ID
+        pub fn get_beacon_deadlines(
+            &self,
+            id: &str,
+        ) -> std::result::Result<HashMap<String, u32>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT beacon, expires FROM {} WHERE id = (?)",
+                self.table_name
+            ))?;
+            let results = q.query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+            results.collect()
+        }
+        /// Retrieves the settings of a user by its ID, returns None if none were stored yet.
+        pub fn get_settings_by_id(
+            &self,
+            id: &str,
+        ) -> std::result::Result<Option<UserSettings>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT id, interval, policy, audit_entries, audit_max_age FROM {} WHERE id = (?)",
+                self.table_name
+            ))?;
+            let mut results = q.query_map([id], |row| {
+                Ok(UserSettings {
+                    id: row.get(0)?,
+                    interval: row.get(1)?,
+                    policy: row.get(2)?,
+                    audit_entries: row.get(3)?,
+                    audit_max_age: row.get(4)?,
+                })
+            })?;
+            match results.next() {
+                Some(val) => Ok(Some(val?)),
+                None => Ok(None),
+            }
+        }
+        /// Stores the given settings, replacing the existing settings of that user
+        pub fn set_settings(
+            &self,
+            settings: &UserSettings,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {} (id, interval, policy, audit_entries, audit_max_age) VALUES ((?), (?), (?), (?), (?))",
+                    self.table_name
+                ),
+                rusqlite::params![
+                    settings.id,
+                    settings.interval,
+                    settings.policy,
+                    settings.audit_entries,
+                    settings.audit_max_age
+                ],
+            )?;
+            Ok(())
+        }
+        /// Stores the given alarm, replacing the existing alarm of that user
+        pub fn set_alarm(&self, alarm: &Alarm) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {} (id, ack_code, raised, grace_until, warned, contacts_notified, admin_notified, acknowledged) VALUES ((?), (?), (?), (?), (?), (?), (?), (?))",
+                    self.table_name
+                ),
+                rusqlite::params![
+                    alarm.id,
+                    alarm.ack_code,
+                    alarm.raised,
+                    alarm.grace_until,
+                    alarm.warned,
+                    alarm.contacts_notified,
+                    alarm.admin_notified,
+                    alarm.acknowledged
+                ],
+            )?;
+            Ok(())
+        }
+        /// Retrieves the alarms matching the given condition
+        fn get_alarms_where(
+            &self,
+            condition: &str,
+            params: &[&dyn rusqlite::ToSql],
+        ) -> std::result::Result<Vec<Alarm>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT id, ack_code, raised, grace_until, warned, contacts_notified, admin_notified, acknowledged FROM {} WHERE {}",
+                self.table_name, condition
+            ))?;
+            let results = q.query_map(params, |row| {
+                Ok(Alarm {
+                    id: row.get(0)?,
+                    ack_code: row.get(1)?,
+                    raised: row.get(2)?,
+                    grace_until: row.get(3)?,
+                    warned: row.get(4)?,
+                    contacts_notified: row.get(5)?,
+                    admin_notified: row.get(6)?,
+                    acknowledged: row.get(7)?,
+                })
+            })?;
+
+            results.collect()
+        }
+        /// Retrieves the alarm of a user by its ID, returns None if none was raised.
+        pub fn get_alarm_by_id(
+            &self,
+            id: &str,
+        ) -> std::result::Result<Option<Alarm>, rusqlite::Error> {
+            Ok(self.get_alarms_where("id = (?)", &[&id])?.pop())
+        }
+        /// Retrieves an alarm by its acknowledgement code, returns None if none was found.
+        pub fn get_alarm_by_ack_code(
+            &self,
+            ack_code: &str,
+        ) -> std::result::Result<Option<Alarm>, rusqlite::Error> {
+            Ok(self.get_alarms_where("ack_code = (?)", &[&ack_code])?.pop())
+        }
+        /// Retrieves all alarms which are neither acknowledged nor fully escalated
+        pub fn get_active_alarms(&self) -> std::result::Result<Vec<Alarm>, rusqlite::Error> {
+            self.get_alarms_where("acknowledged IS NULL AND admin_notified IS NULL", &[])
+        }
+        /// Adds a new (inactive) contact for the given user, then returns the full contact
+        pub fn add_contact(
+            &self,
+            user_id: &str,
+            name: &str,
+            email: &str,
+            message: &str,
+        ) -> std::result::Result<Contact, rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "INSERT INTO {} (user_id, name, email, message, active) VALUES ((?), (?), (?), (?), 0)",
+                    self.table_name
+                ),
+                [user_id, name, email, message],
+            )?;
+            Ok(Contact {
+                id: self.connection.last_insert_rowid() as u32,
+                user_id: user_id.to_string(),
+                name: name.to_string(),
+                email: email.to_string(),
+                message: message.to_string(),
+                active: false,
+            })
+        }
+        /// Retrieves the contacts matching the given condition
+        fn get_contacts_where(
+            &self,
+            condition: &str,
+            params: &[&dyn rusqlite::ToSql],
+        ) -> std::result::Result<Vec<Contact>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT id, user_id, name, email, message, active FROM {} WHERE {} ORDER BY id",
+                self.table_name, condition
+            ))?;
+            let results = q.query_map(params, |row| {
+                Ok(Contact {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    name: row.get(2)?,
+                    email: row.get(3)?,
+                    message: row.get(4)?,
+                    active: row.get(5)?,
+                })
+            })?;
+
+            results.collect()
+        }
+        /// Select all contacts of the given user
+        pub fn get_contacts_by_user(
+            &self,
+            user_id: &str,
+        ) -> std::result::Result<Vec<Contact>, rusqlite::Error> {
+            self.get_contacts_where("user_id = (?)", &[&user_id])
+        }
+        /// Retrieves a contact by its id, returns None if none was found.
+        pub fn get_contact_by_id(
+            &self,
+            id: u32,
+        ) -> std::result::Result<Option<Contact>, rusqlite::Error> {
+            Ok(self.get_contacts_where("id = (?)", &[&id])?.pop())
+        }
+        /// Marks the contact with the given id as active, after it confirmed to receive notifications
+        pub fn activate_contact(&self, id: u32) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!("UPDATE {} SET active = 1 WHERE id = (?)", self.table_name),
+                [id],
+            )?;
+            Ok(())
+        }
+        /// Deletes the contact with the given id, if it belongs to the given user.
+        /// Returns false if no such contact was found.
+        pub fn delete_contact(
+            &self,
+            user_id: &str,
+            id: u32,
+        ) -> std::result::Result<bool, rusqlite::Error> {
+            let changed = self.connection.execute(
+                &format!(
+                    "DELETE FROM {} WHERE id = (?) AND user_id = (?)",
+                    self.table_name
+                ),
+                rusqlite::params![id, user_id],
+            )?;
+            Ok(changed > 0)
+        }
+        /// Adds a new device with a fresh token for the given user,
+        /// returns the device along with its token
+        pub fn add_device(
+            &self,
+            user_id: &str,
+            name: &str,
+        ) -> std::result::Result<(Device, String), rusqlite::Error> {
+            let token = Self::generate_token();
+            let (prefix, salt, hash) = hash_new_token(&token);
+            let created = u32::try_from(Utc::now().timestamp()).expect("Time went backwards");
+            self.connection.execute(
+                &format!(
+                    "INSERT INTO {} (user_id, name, token_prefix, token_salt, token_hash, created) VALUES ((?), (?), (?), (?), (?), (?))",
+                    self.table_name
+                ),
+                rusqlite::params![user_id, name, prefix, salt, hash, created],
+            )?;
+            Ok((
+                Device {
+                    id: self.connection.last_insert_rowid() as u32,
+                    user_id: user_id.to_string(),
+                    name: name.to_string(),
+                    created,
+                    last_seen: None,
+                },
+                token,
+            ))
+        }
+        /// Retrieves the devices of the given user, oldest first
+        pub fn get_devices_by_user(
+            &self,
+            user_id: &str,
+        ) -> std::result::Result<Vec<Device>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT id, user_id, name, created, last_seen FROM {} WHERE user_id = (?) ORDER BY id",
+                self.table_name
+            ))?;
+            let results = q.query_map([user_id], |row| {
+                Ok(Device {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    name: row.get(2)?,
+                    created: row.get(3)?,
+                    last_seen: row.get(4)?,
+                })
+            })?;
+            results.collect()
+        }
+        /// Retrieves the device the given token belongs to, returns None if the token is invalid.
+        /// Works like `get_user_by_token`.
+        pub fn get_device_by_token(
+            &self,
+            token: &str,
+        ) -> std::result::Result<Option<Device>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT id, user_id, name, created, last_seen, token_salt, token_hash FROM {} WHERE token_prefix = (?)",
+                self.table_name
+            ))?;
+            let candidates = q.query_map([token_prefix(token)], |row| {
+                Ok((
+                    Device {
+                        id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        name: row.get(2)?,
+                        created: row.get(3)?,
+                        last_seen: row.get(4)?,
+                    },
+                    row.get::<_, String>(5)?,
+                    row.get::<_, String>(6)?,
+                ))
+            })?;
+            for candidate in candidates {
+                let (device, salt, hash) = candidate?;
+                if signing::verify_token(token, &salt, &hash) {
+                    return Ok(Some(device));
+                }
+            }
+            Ok(None)
+        }
+        /// Sets the time the device with the given id was last seen
+        pub fn touch_device(
+            &self,
+            id: u32,
+            timestamp: u32,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "UPDATE {} SET last_seen = (?) WHERE id = (?)",
+                    self.table_name
+                ),
+                [timestamp, id],
+            )?;
+            Ok(())
+        }
+        /// Deletes the device with the given id, if it belongs to the given user.
+        /// Its token becomes invalid immediately. Returns false if no such device was found.
+        pub fn delete_device(
+            &self,
+            user_id: &str,
+            id: u32,
+        ) -> std::result::Result<bool, rusqlite::Error> {
+            let changed = self.connection.execute(
+                &format!(
+                    "DELETE FROM {} WHERE id = (?) AND user_id = (?)",
+                    self.table_name
+                ),
+                rusqlite::params![id, user_id],
+            )?;
+            Ok(changed > 0)
+        }
+        /// Appends the given entry to the audit log of the given user, see `append_audit_entries`
+        pub fn append_audit(
+            &self,
+            user_id: &str,
+            record: AuditRecord,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            self.append_audit_entries(user_id, vec![record])
+        }
+        /// Appends the given entries to the audit log of the given user, each chained to the
+        /// hash of the entry before it. The log is locked while the head is read, so concurrent
+        /// writers can't chain two entries to the same head.
+        /// The audit log is always written to the audit table, whatever table this state is for.
+        pub fn append_audit_entries(
+            &self,
+            user_id: &str,
+            records: Vec<AuditRecord>,
+        ) -> std::result::Result<(), rusqlite::Error> {
+            let transaction =
+                Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+            append_audit_chain(&transaction, user_id, records)?;
+            transaction.commit()
+        }
+        /// Appends the entries read from the given audit log file to the audit log of the given
+        /// user, see `append_audit_entries`. The file is recorded along with the entries, so it's
+        /// never imported twice, even if it couldn't be deleted afterwards.
+        /// Returns false if the file was imported before, nothing is appended then.
+        pub fn import_audit_entries(
+            &self,
+            user_id: &str,
+            file: &str,
+            records: Vec<AuditRecord>,
+            time: u32,
+        ) -> std::result::Result<bool, rusqlite::Error> {
+            let transaction =
+                Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+            let recorded = transaction.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO {} (file, time) VALUES ((?), (?))",
+                    AUDIT_IMPORTS_TABLE
+                ),
+                rusqlite::params![file, time],
+            )?;
+            if recorded == 0 {
+                return Ok(false);
+            }
+            append_audit_chain(&transaction, user_id, records)?;
+            transaction.commit()?;
+            Ok(true)
+        }
+        /// Appends a checkpoint signing the head of the audit log of the given user.
+        /// Returns false if there was nothing to sign, as the log is empty or already signed.
+        pub fn checkpoint_audit(
+            &self,
+            user_id: &str,
+            key: &ServerKey,
+            time: u32,
+        ) -> std::result::Result<bool, rusqlite::Error> {
+            let transaction =
+                Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+            let head = match audit_head(&transaction, user_id)? {
+                // Anchors are signed already
+                Some((hash, kind))
+                    if !matches!(kind, AuditKind::Checkpoint | AuditKind::Anchor) =>
+                {
+                    hash
+                }
+                _ => return Ok(false),
+            };
+            let record = AuditRecord::checkpoint(user_id, &head, key, time);
+            insert_audit(&transaction, user_id, head, record)?;
+            transaction.commit()?;
+            Ok(true)
+        }
+        /// Select the entries of the audit log of the given user as they are stored,
+        /// oldest first. This has to be called on the audit table.
+        pub fn get_audit_lines(
+            &self,
+            user_id: &str,
+        ) -> std::result::Result<Vec<String>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT record FROM {} WHERE user_id = (?) ORDER BY id",
+                self.table_name
+            ))?;
+            let results = q.query_map([user_id], |row| row.get(0))?;
+
+            results.collect()
+        }
+        /// Select the entries of the audit log of the given user, oldest first.
+        /// This has to be called on the audit table.
+        pub fn get_audit_records(
+            &self,
+            user_id: &str,
+        ) -> std::result::Result<Vec<AuditRecord>, rusqlite::Error> {
+            self.get_audit_lines(user_id)?
+                .iter()
+                .map(|line| parse_audit_record(line))
+                .collect()
+        }
+        /// Select the entries of the audit log of the given user matching the query, newest
+        /// first. A page holds at most `audit_log::MAX_PAGE_SIZE` entries.
+        /// This has to be called on the audit table.
+        pub fn query_audit(
+            &self,
+            user_id: &str,
+            query: &AuditQuery,
+        ) -> std::result::Result<AuditEntries, rusqlite::Error> {
+            let filter = "WHERE user_id = (?1) AND ((?2) IS NULL OR time >= (?2)) \
+                AND ((?3) IS NULL OR time <= (?3)) AND ((?4) IS NULL OR kind = (?4))";
+            let params = rusqlite::params![user_id, query.from, query.to, query.kind];
+            let total: i64 = self.connection.query_row(
+                &format!("SELECT COUNT(*) FROM {} {}", self.table_name, filter),
+                params,
+                |row| row.get(0),
+            )?;
+            let limit = query
+                .limit
+                .unwrap_or(audit_log::DEFAULT_PAGE_SIZE)
+                .min(audit_log::MAX_PAGE_SIZE);
+            let mut q = self.connection.prepare(&format!(
+                "SELECT record FROM {} {} ORDER BY id DESC LIMIT (?5) OFFSET (?6)",
+                self.table_name, filter
+            ))?;
+            let entries = q
+                .query_map(
+                    rusqlite::params![
+                        user_id,
+                        query.from,
+                        query.to,
+                        query.kind,
+                        limit as i64,
+                        query.offset as i64
+                    ],
+                    |row| parse_audit_record(&row.get::<_, String>(0)?),
+                )?
+                .collect::<std::result::Result<Vec<AuditRecord>, _>>()?;
+            Ok(AuditEntries {
+                total: total as usize,
+                offset: query.offset,
+                entries,
+            })
+        }
+        /// Deletes the entries of every audit log exceeding its retention, which is the
+        /// retention of the server unless the user chose to keep fewer or younger entries.
+        /// Entries are removed from the start of the log, an anchor signed with the given key
+        /// takes the place of the newest removed entry so the rest of the log can be verified.
+        /// Returns the number of deleted entries. This has to be called on the audit table.
+        pub fn compact_audit(
+            &self,
+            key: &ServerKey,
+            max_entries: u32,
+            max_age: Option<u32>,
+            time: u32,
+        ) -> std::result::Result<usize, rusqlite::Error> {
+            let transaction =
+                Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+            // The newest entry to remove of every log, anchors don't count as entries.
+            // The scalar MIN is NULL if any argument is, so a missing age falls back to the other
+            let cuts: Vec<(String, i64, String)> = transaction
+                .prepare(&format!(
+                    "SELECT user_id, id, hash FROM {0} WHERE id IN (SELECT MAX(id) FROM \
+                    (SELECT a.id, a.user_id, a.time, \
+                    ROW_NUMBER() OVER (PARTITION BY a.user_id ORDER BY a.id DESC) AS position, \
+                    MIN(COALESCE(s.audit_entries, (?1)), (?1)) AS keep, \
+                    COALESCE(MIN(s.audit_max_age, (?2)), s.audit_max_age, (?2)) AS max_age \
+                    FROM {0} a LEFT JOIN {1} s ON s.id = a.user_id WHERE a.kind != (?4)) \
+                    WHERE position > keep OR time < (?3) - max_age GROUP BY user_id)",
+                    self.table_name, SETTINGS_TABLE
+                ))?
+                .query_map(
+                    rusqlite::params![max_entries, max_age, time, AuditKind::Anchor],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?
+                .collect::<std::result::Result<_, _>>()?;
+            let mut removed = 0;
+            for (user_id, id, hash) in cuts {
+                removed += transaction.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE user_id = (?) AND id <= (?) AND kind != (?)",
+                        self.table_name
+                    ),
+                    rusqlite::params![user_id, id, AuditKind::Anchor],
+                )?;
+                transaction.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE user_id = (?) AND kind = (?)",
+                        self.table_name
+                    ),
+                    rusqlite::params![user_id, AuditKind::Anchor],
+                )?;
+                // The anchor is stored with the hash of the removed entry, so entries appended
+                // to a log of which nothing is left are chained to it just like the kept ones
+                let record = AuditRecord::anchor(&user_id, &hash, key, time);
+                let line = serde_json::to_string(&record)
+                    .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+                transaction.execute(
+                    &format!(
+                        "INSERT INTO {} (id, user_id, time, kind, record, hash) \
+                        VALUES ((?), (?), (?), (?), (?), (?))",
+                        self.table_name
+                    ),
+                    rusqlite::params![id, user_id, record.time, record.kind, line, hash],
+                )?;
             }
+            transaction.commit()?;
+            Ok(removed)
+        }
+        /// Deletes the audit logs of all users whose contacts were notified more than the given
+        /// number of seconds ago. Returns the number of deleted entries.
+        /// This has to be called on the audit table.
+        pub fn purge_archived_audit(
+            &self,
+            archive_period: u32,
+            time: u32,
+        ) -> std::result::Result<usize, rusqlite::Error> {
+            self.connection.execute(
+                &format!(
+                    "DELETE FROM {0} WHERE user_id IN (SELECT id FROM {1} u WHERE state = (?1) \
+                    AND (SELECT MAX(timestamp) FROM {2} WHERE user_id = u.id AND new_state = (?1)) <= (?2))",
+                    self.table_name, USERS_TABLE, STATE_HISTORY_TABLE
+                ),
+                rusqlite::params![UserState::Notified, i64::from(time) - i64::from(archive_period)],
+            )
+        }
+        /// Select the users whose audit log was written to since its last checkpoint.
+        /// This has to be called on the audit table.
+        pub fn get_unsigned_audit_users(
+            &self,
+        ) -> std::result::Result<Vec<String>, rusqlite::Error> {
+            let mut q = self.connection.prepare(&format!(
+                "SELECT user_id FROM {0} WHERE id IN (SELECT MAX(id) FROM {0} GROUP BY user_id) \
+                AND kind NOT IN ((?), (?))",
+                self.table_name
+            ))?;
+            let results =
+                q.query_map([AuditKind::Checkpoint, AuditKind::Anchor], |row| row.get(0))?;
+
+            results.collect()
+        }
+        /// Delete all entries belonging to the given user.
+        /// This works on every table with a user_id column (contacts, state_history, devices, audit).
+        pub fn delete_by_user_id(&self, user_id: &str) -> std::result::Result<(), rusqlite::Error> {
+            self.connection.execute(
+                &format!("DELETE FROM {} WHERE user_id = (?)", self.table_name),
+                [user_id],
+            )?;
+            Ok(())
         }
         /// Delete all verification entries which are expired
         pub fn delete_outtimed_verifications(&self) -> std::result::Result<(), rusqlite::Error> {
@@ -348,13 +1776,15 @@ pub mod sqlite_handler {
 
 #[cfg(test)]
 mod tests {
-    use crate::data::data_forms::User;
-    use crate::sqlite_handler::DatabaseState;
+    use crate::data::data_forms::{
+        Alarm, BeaconPolicy, TransitionCause, TransitionError, User, UserSettings, UserState,
+    };
+    use crate::sqlite_handler::{migrate, migrate_database, DatabaseState};
 
-    use chrono::Local;
+    use chrono::{Local, Utc};
     use env_logger::Builder;
     use log::LevelFilter;
-    use std::io::Write;
+    use std::{convert::TryFrom, io::Write};
 
     fn init_logging() {
         Builder::new()
@@ -371,28 +1801,95 @@ mod tests {
             .filter(None, LevelFilter::Debug)
             .init();
     }
+    /// Returns the path of a fresh database for the test of the given name
+    fn temp_db(name: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("dmnb_{}.sqlite", name))
+            .to_str()
+            .unwrap()
+            .to_string();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+        path
+    }
 
     #[test]
     fn create_new_user() {
         //init_logging();
 
         log::debug!("Testing New User Creation");
-        let db = DatabaseState::init("./dmnb.sqlite".to_string()).unwrap();
-        let user = db.new_user(&"foo@example.com".to_string()).unwrap();
-        assert_eq!(user.email, "foo@example.com".to_string())
+        let path = temp_db("create_new_user");
+        let db = DatabaseState::init(path.clone()).unwrap();
+        db.create_table_for_user().unwrap();
+        DatabaseState::init_with_table_name(path, "state_history".to_string())
+            .unwrap()
+            .create_table_for_state_history()
+            .unwrap();
+        let (user, token) = db.new_user(&"foo@example.com".to_string()).unwrap();
+        assert_eq!(user.email, "foo@example.com".to_string());
+        assert_ne!(user.id, token);
     }
     #[test]
     fn basic_table_operations() {
         init_logging();
         log::debug!("Creating new table called test for tesiting operation");
-        let db =
-            DatabaseState::init_with_table_name("./dmnb.sqlite".to_string(), "test".to_string())
-                .unwrap();
+        let path = temp_db("basic_table_operations");
+        let db = DatabaseState::init_with_table_name(path.clone(), "test".to_string()).unwrap();
         db.create_table_for_user().unwrap();
+        let history_db =
+            DatabaseState::init_with_table_name(path, "state_history".to_string()).unwrap();
+        history_db.create_table_for_state_history().unwrap();
 
         log::debug!("Creating new&empty user for testing");
-        let test_user = db.new_user(&"foobar@example.com".to_string()).unwrap();
+        let (test_user, token) = db.new_user(&"foobar@example.com".to_string()).unwrap();
         let empty_user = User::empty();
+        log::debug!("Fetching new User by Token");
+        assert_eq!(
+            db.get_user_by_token(&token).unwrap(),
+            Some(test_user.clone())
+        );
+        assert_eq!(db.get_user_by_token(&test_user.id).unwrap(), None);
+        let mut wrong_token = token.clone();
+        wrong_token.replace_range(60.., "0000");
+        assert_eq!(db.get_user_by_token(&wrong_token).unwrap(), None);
+        log::debug!("Rotating the Token of the new User");
+        let new_token = DatabaseState::generate_token();
+        db.set_user_token(&test_user.id, &new_token).unwrap();
+        assert_eq!(db.get_user_by_token(&token).unwrap(), None);
+        assert_eq!(
+            db.get_user_by_token(&new_token).unwrap(),
+            Some(test_user.clone())
+        );
+        log::debug!("Rotating the Token with an overlap window");
+        let now = u32::try_from(Utc::now().timestamp()).unwrap();
+        let overlap_token = DatabaseState::generate_token();
+        db.rotate_user_token(&test_user.id, &overlap_token, now + 60)
+            .unwrap();
+        assert_eq!(
+            db.get_user_by_token(&new_token).unwrap(),
+            Some(test_user.clone())
+        );
+        assert_eq!(
+            db.get_user_by_token(&overlap_token).unwrap(),
+            Some(test_user.clone())
+        );
+        let expired_token = DatabaseState::generate_token();
+        db.rotate_user_token(&test_user.id, &expired_token, now - 1)
+            .unwrap();
+        assert_eq!(db.get_user_by_token(&new_token).unwrap(), None);
+        assert_eq!(db.get_user_by_token(&overlap_token).unwrap(), None);
+        assert_eq!(
+            db.get_user_by_token(&expired_token).unwrap(),
+            Some(test_user.clone())
+        );
+        log::debug!("Revoking the Token");
+        assert!(db.has_token(&test_user.id).unwrap());
+        db.revoke_user_tokens(&test_user.id).unwrap();
+        assert!(!db.has_token(&test_user.id).unwrap());
+        assert_eq!(db.get_user_by_token(&expired_token).unwrap(), None);
+        db.set_user_token(&test_user.id, &token).unwrap();
+        assert!(db.has_token(&test_user.id).unwrap());
         log::debug!("Fetching new User by Id and Email");
         assert_eq!(
             match db.get_user_by_id(&test_user.id).unwrap() {
@@ -424,18 +1921,85 @@ mod tests {
             0
         );
         log::debug!("Updating State of the new User");
-        assert_eq!(db.update_state_user(&test_user.id, 2), Ok(true));
+        assert_eq!(
+            db.update_state_user(&test_user.id, UserState::Paused, TransitionCause::Pause),
+            Ok(Ok(UserState::Paused))
+        );
         let upd_user = User {
             id: test_user.id.clone(),
             email: test_user.email.clone(),
-            state: 2,
+            state: UserState::Paused,
         };
-        log::debug!("Get Users by state 2 and -1");
-        assert_eq!(db.get_user_by_state(2), Ok(vec![upd_user]));
-        assert_eq!(db.get_user_by_state(-1), Ok(vec![]));
+        log::debug!("Reviving the new User");
+        assert!(db
+            .update_state_user(&test_user.id, UserState::Active, TransitionCause::Revival)
+            .unwrap()
+            .is_err());
+        assert_eq!(
+            db.update_state_user(&test_user.id, UserState::Deceased, TransitionCause::Sign),
+            Ok(Ok(UserState::Deceased))
+        );
+        assert_eq!(
+            db.update_state_user(&test_user.id, UserState::Paused, TransitionCause::Pause),
+            Ok(Err(TransitionError {
+                from: UserState::Deceased,
+                to: UserState::Paused
+            }))
+        );
+        // Only a revival brings the user back
+        assert!(db
+            .update_state_user(&test_user.id, UserState::Active, TransitionCause::Ilive)
+            .unwrap()
+            .is_err());
+        assert_eq!(
+            db.update_state_user(&test_user.id, UserState::Active, TransitionCause::Revival),
+            Ok(Ok(UserState::Active))
+        );
+        assert_eq!(
+            db.update_state_user(&test_user.id, UserState::Paused, TransitionCause::Pause),
+            Ok(Ok(UserState::Paused))
+        );
+        assert!(db
+            .update_state_user(&empty_user.id, UserState::Active, TransitionCause::Ilive)
+            .unwrap()
+            .is_err());
+        log::debug!("Checking the state history of the new User");
+        let history: Vec<(UserState, UserState, TransitionCause)> = history_db
+            .get_state_history(&test_user.id)
+            .unwrap()
+            .into_iter()
+            .map(|change| (change.old_state, change.new_state, change.cause))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                (
+                    UserState::Unknown,
+                    UserState::Active,
+                    TransitionCause::Registration
+                ),
+                (UserState::Active, UserState::Paused, TransitionCause::Pause),
+                (
+                    UserState::Paused,
+                    UserState::Deceased,
+                    TransitionCause::Sign
+                ),
+                (
+                    UserState::Deceased,
+                    UserState::Active,
+                    TransitionCause::Revival
+                ),
+                (UserState::Active, UserState::Paused, TransitionCause::Pause),
+            ]
+        );
+        log::debug!("Get Users by state Paused and Unknown");
+        assert_eq!(db.get_user_by_state(UserState::Paused), Ok(vec![upd_user]));
+        assert_eq!(db.get_user_by_state(UserState::Unknown), Ok(vec![]));
 
         log::debug!("Deleting the new User");
         db.delete_user(&test_user.id).unwrap();
+        history_db.delete_by_user_id(&test_user.id).unwrap();
+        assert_eq!(history_db.get_state_history(&test_user.id), Ok(vec![]));
         assert_eq!(
             match db.get_user_by_id(&test_user.id).unwrap() {
                 Some(_) =>
@@ -449,4 +2013,437 @@ mod tests {
         db.delete_table().unwrap();
         db.delete_table().unwrap();
     }
+    #[test]
+    fn deadline_operations() {
+        log::debug!("Creating new table called test_deadlines for testing deadlines");
+        let path = temp_db("deadline_operations");
+        let db = DatabaseState::init_with_table_name(path.clone(), "test_deadlines".to_string())
+            .unwrap();
+        db.create_table_for_deadline().unwrap();
+        // The beacon policies are read from the settings table
+        let settings_db =
+            DatabaseState::init_with_table_name(path, "settings".to_string()).unwrap();
+        settings_db.create_table_for_settings().unwrap();
+
+        log::debug!("Setting, replacing and deleting deadlines");
+        db.set_deadline("foo", "default", 100).unwrap();
+        db.set_deadline("bar", "default", 200).unwrap();
+        db.set_deadline("foo", "default", 300).unwrap();
+        let deadlines = db.get_all_deadlines().unwrap();
+        assert_eq!(deadlines.len(), 2);
+        assert_eq!(deadlines.get("foo"), Some(&300));
+        assert_eq!(deadlines.get("bar"), Some(&200));
+
+        log::debug!("Combining the deadlines of multiple beacons");
+        db.set_deadline("foo", "watch", 250).unwrap();
+        assert_eq!(db.get_beacon_deadlines("foo").unwrap().len(), 2);
+        assert_eq!(db.get_deadline("foo"), Ok(Some(300)));
+        let mut settings = UserSettings::default_for("foo");
+        settings.policy = BeaconPolicy::All;
+        settings_db.set_settings(&settings).unwrap();
+        assert_eq!(db.get_deadline("foo"), Ok(Some(250)));
+        assert_eq!(db.get_all_deadlines().unwrap().get("foo"), Some(&250));
+        assert_eq!(db.delete_beacon_deadline("foo", "phone"), Ok(false));
+        assert_eq!(db.delete_beacon_deadline("foo", "watch"), Ok(true));
+        assert_eq!(db.get_deadline("foo"), Ok(Some(300)));
+        settings_db.delete_user(&"foo".to_string()).unwrap();
+
+        db.delete_deadline("foo").unwrap();
+        let deadlines = db.get_all_deadlines().unwrap();
+        assert_eq!(deadlines.get("foo"), None);
+        assert_eq!(db.get_deadline("foo"), Ok(None));
+        assert_eq!(deadlines.len(), 1);
+
+        log::debug!("Deleting test Table");
+        db.delete_table().unwrap();
+    }
+    #[test]
+    fn settings_operations() {
+        log::debug!("Creating new table called test_settings for testing settings");
+        let db = DatabaseState::init_with_table_name(
+            temp_db("settings_operations"),
+            "test_settings".to_string(),
+        )
+        .unwrap();
+        db.create_table_for_settings().unwrap();
+
+        log::debug!("Storing, replacing and deleting settings");
+        assert_eq!(db.get_settings_by_id("foo"), Ok(None));
+        let mut settings = UserSettings::default_for("foo");
+        db.set_settings(&settings).unwrap();
+        assert_eq!(db.get_settings_by_id("foo"), Ok(Some(settings.clone())));
+        settings.interval = Some(3600);
+        settings.policy = BeaconPolicy::All;
+        settings.audit_entries = Some(100);
+        settings.audit_max_age = Some(86400);
+        db.set_settings(&settings).unwrap();
+        assert_eq!(db.get_settings_by_id("foo"), Ok(Some(settings.clone())));
+
+        db.delete_user(&"foo".to_string()).unwrap();
+        assert_eq!(db.get_settings_by_id("foo"), Ok(None));
+
+        log::debug!("Deleting test Table");
+        db.delete_table().unwrap();
+    }
+    #[test]
+    fn alarm_operations() {
+        log::debug!("Creating new table called test_alarms for testing alarms");
+        let db = DatabaseState::init_with_table_name(
+            temp_db("alarm_operations"),
+            "test_alarms".to_string(),
+        )
+        .unwrap();
+        db.create_table_for_alarm().unwrap();
+
+        let mut alarm = Alarm {
+            id: "foo".to_string(),
+            ack_code: "bar".to_string(),
+            raised: 100,
+            grace_until: 200,
+            warned: Some(100),
+            contacts_notified: None,
+            admin_notified: None,
+            acknowledged: None,
+        };
+        db.set_alarm(&alarm).unwrap();
+        assert_eq!(db.get_alarm_by_id("foo"), Ok(Some(alarm.clone())));
+        assert_eq!(db.get_alarm_by_ack_code("bar"), Ok(Some(alarm.clone())));
+        assert_eq!(db.get_alarm_by_ack_code("foo"), Ok(None));
+        assert_eq!(db.get_active_alarms(), Ok(vec![alarm.clone()]));
+
+        log::debug!("Acknowledging the alarm");
+        alarm.acknowledged = Some(150);
+        db.set_alarm(&alarm).unwrap();
+        assert_eq!(db.get_alarm_by_id("foo"), Ok(Some(alarm)));
+        assert_eq!(db.get_active_alarms(), Ok(vec![]));
+
+        log::debug!("Deleting test Table");
+        db.delete_table().unwrap();
+    }
+    #[test]
+    fn contact_operations() {
+        log::debug!("Creating new table called test_contacts for testing contacts");
+        let db = DatabaseState::init_with_table_name(
+            temp_db("contact_operations"),
+            "test_contacts".to_string(),
+        )
+        .unwrap();
+        db.create_table_for_contact().unwrap();
+
+        log::debug!("Adding contacts to two users");
+        let first = db
+            .add_contact("foo", "First", "first@example.com", "Hello")
+            .unwrap();
+        let second = db
+            .add_contact("foo", "Second", "second@example.com", "")
+            .unwrap();
+        let other = db
+            .add_contact("bar", "Other", "other@example.com", "")
+            .unwrap();
+        assert_eq!(
+            db.get_contacts_by_user("foo"),
+            Ok(vec![first.clone(), second.clone()])
+        );
+
+        log::debug!("Deleting contacts");
+        assert!(!first.active);
+        db.activate_contact(first.id).unwrap();
+        let first = db.get_contact_by_id(first.id).unwrap().unwrap();
+        assert!(first.active);
+        assert_eq!(db.delete_contact("foo", other.id), Ok(false));
+        assert_eq!(db.delete_contact("foo", first.id), Ok(true));
+        assert_eq!(db.get_contacts_by_user("foo"), Ok(vec![second]));
+        db.delete_by_user_id("foo").unwrap();
+        assert_eq!(db.get_contacts_by_user("foo"), Ok(vec![]));
+        assert_eq!(db.get_contacts_by_user("bar"), Ok(vec![other]));
+
+        log::debug!("Deleting test Table");
+        db.delete_table().unwrap();
+    }
+    #[test]
+    fn verification_attempts() {
+        log::debug!("Creating new table called test_verification for testing attempts");
+        let db = DatabaseState::init_with_table_name(
+            temp_db("verification_attempts"),
+            "test_verification".to_string(),
+        )
+        .unwrap();
+        db.create_table_for_verification().unwrap();
+
+        log::debug!("Failing to submit a code until it's invalidated");
+        let email = "foo@example.com".to_string();
+        db.generate_verification_code(email.clone(), true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.count_failed_attempt(&email, 3), Ok(false));
+        assert_eq!(db.count_failed_attempt(&email, 3), Ok(false));
+        assert!(db.get_verification_by_email(&email).unwrap().is_some());
+        assert_eq!(db.count_failed_attempt(&email, 3), Ok(true));
+        assert_eq!(db.get_verification_by_email(&email), Ok(None));
+        // Without a code there is nothing to invalidate
+        assert_eq!(db.count_failed_attempt(&email, 3), Ok(false));
+
+        log::debug!("Deleting test Table");
+        db.delete_table().unwrap();
+    }
+    #[test]
+    fn email_change_codes() {
+        let path = temp_db("email_change_codes");
+        migrate_database(&path).unwrap();
+        let db =
+            DatabaseState::init_with_table_name(path.clone(), "email_verification".to_string())
+                .unwrap();
+
+        log::debug!("Requesting a new email replaces the code sent before");
+        let first = db
+            .generate_email_change_code("foo", "first@example.com".to_string())
+            .unwrap()
+            .unwrap();
+        let second = db
+            .generate_email_change_code("foo", "second@example.com".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.get_verification_user(first.code), Ok(None));
+        assert_eq!(
+            db.get_verification_user(second.code),
+            Ok(Some("foo".to_string()))
+        );
+        // An email awaiting the confirmation of another user can't be requested
+        assert_eq!(
+            db.generate_email_change_code("bar", "second@example.com".to_string()),
+            Ok(None)
+        );
+        assert_eq!(
+            db.verify_verification_code(second.code),
+            Ok(Some("second@example.com".to_string()))
+        );
+        assert_eq!(db.get_verification_user(second.code), Ok(None));
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn device_operations() {
+        log::debug!("Creating new table called test_devices for testing devices");
+        let db = DatabaseState::init_with_table_name(
+            temp_db("device_operations"),
+            "test_devices".to_string(),
+        )
+        .unwrap();
+        db.create_table_for_device().unwrap();
+
+        log::debug!("Adding devices to two users");
+        let (phone, phone_token) = db.add_device("foo", "Phone").unwrap();
+        let (watch, watch_token) = db.add_device("foo", "Watch").unwrap();
+        let (other, _) = db.add_device("bar", "Phone").unwrap();
+        assert_ne!(phone_token, watch_token);
+        assert_eq!(
+            db.get_devices_by_user("foo"),
+            Ok(vec![phone.clone(), watch.clone()])
+        );
+
+        log::debug!("Looking devices up by their token");
+        assert_eq!(
+            db.get_device_by_token(&phone_token),
+            Ok(Some(phone.clone()))
+        );
+        assert_eq!(
+            db.get_device_by_token(&watch_token),
+            Ok(Some(watch.clone()))
+        );
+        assert_eq!(db.get_device_by_token("foo"), Ok(None));
+        assert_eq!(phone.last_seen, None);
+        db.touch_device(phone.id, 1600000000).unwrap();
+        assert_eq!(
+            db.get_device_by_token(&phone_token)
+                .unwrap()
+                .unwrap()
+                .last_seen,
+            Some(1600000000)
+        );
+
+        log::debug!("Revoking devices");
+        assert_eq!(db.delete_device("foo", other.id), Ok(false));
+        assert_eq!(db.delete_device("foo", phone.id), Ok(true));
+        assert_eq!(db.get_device_by_token(&phone_token), Ok(None));
+        assert_eq!(db.get_devices_by_user("foo"), Ok(vec![watch]));
+        db.delete_by_user_id("foo").unwrap();
+        assert_eq!(db.get_device_by_token(&watch_token), Ok(None));
+        assert_eq!(db.get_devices_by_user("bar"), Ok(vec![other]));
+
+        log::debug!("Deleting test Table");
+        db.delete_table().unwrap();
+    }
+    #[test]
+    fn migrate_fixture_database() {
+        let path = std::env::temp_dir().join("dmnb_migration_fixture.sqlite");
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(include_str!("../tests/fixtures/unversioned.sql"))
+            .unwrap();
+
+        log::debug!("Upgrading the fixture database");
+        let version = migrate_database(&path).unwrap();
+        assert!(version >= 1);
+        assert_eq!(migrate_database(&path), Ok(version));
+
+        log::debug!("Reading the upgraded fixture database");
+        let db = DatabaseState::init(path.clone()).unwrap();
+        let dead = db
+            .get_user_by_email(&"dead@example.com".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(dead.state, UserState::Notified);
+        let history =
+            DatabaseState::init_with_table_name(path.clone(), "state_history".to_string()).unwrap();
+        let alive = db
+            .get_user_by_email(&"alive@example.com".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(history.get_state_history(&alive.id).unwrap().len(), 1);
+        log::debug!("The old id keeps working as token, but no longer as id");
+        let legacy_token = format!("alive{}", "0".repeat(59));
+        assert_eq!(
+            db.get_user_by_token(&legacy_token).unwrap(),
+            Some(alive.clone())
+        );
+        assert_ne!(alive.id, legacy_token);
+        assert_eq!(db.get_user_by_id(&legacy_token).unwrap(), None);
+        let settings =
+            DatabaseState::init_with_table_name(path.clone(), "settings".to_string()).unwrap();
+        assert_eq!(
+            settings.get_settings_by_id(&alive.id).unwrap(),
+            Some(UserSettings {
+                id: alive.id.clone(),
+                interval: Some(86400),
+                policy: BeaconPolicy::Any,
+                audit_entries: None,
+                audit_max_age: None,
+            })
+        );
+        let deadlines =
+            DatabaseState::init_with_table_name(path.clone(), "deadlines".to_string()).unwrap();
+        assert_eq!(deadlines.get_deadline(&alive.id).unwrap(), Some(1600086400));
+        let alarms =
+            DatabaseState::init_with_table_name(path.clone(), "alarms".to_string()).unwrap();
+        assert_eq!(
+            alarms.get_alarm_by_ack_code("ackcode").unwrap().unwrap().id,
+            dead.id
+        );
+        let contacts =
+            DatabaseState::init_with_table_name(path.clone(), "contacts".to_string()).unwrap();
+        assert_eq!(contacts.get_contacts_by_user(&dead.id).unwrap().len(), 1);
+        let verification =
+            DatabaseState::init_with_table_name(path.clone(), "verification".to_string()).unwrap();
+        assert!(verification
+            .get_verification_by_email(&"new@example.com".to_string())
+            .unwrap()
+            .is_some());
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn delete_account() {
+        let path = temp_db("delete_account");
+        migrate_database(&path).unwrap();
+        let table = |name: &str| {
+            DatabaseState::init_with_table_name(path.clone(), name.to_string()).unwrap()
+        };
+        let db = DatabaseState::init(path.clone()).unwrap();
+        let (user, _) = db.new_user(&"foo@example.com".to_string()).unwrap();
+        let (other, _) = db.new_user(&"bar@example.com".to_string()).unwrap();
+
+        log::debug!("Filling every table with data of both users");
+        for id in [&user.id, &other.id] {
+            table("settings")
+                .set_settings(&UserSettings::default_for(id))
+                .unwrap();
+            table("deadlines").set_deadline(id, "default", 100).unwrap();
+            table("devices").add_device(id, "Phone").unwrap();
+        }
+        let contacts = table("contacts");
+        contacts
+            .add_contact(&user.id, "Own", "own@example.com", "")
+            .unwrap();
+        contacts
+            .add_contact(&user.id, "Shared", "shared@example.com", "")
+            .unwrap();
+        contacts
+            .add_contact(&other.id, "Shared", "shared@example.com", "")
+            .unwrap();
+        let contact_verification = table("contact_verification");
+        for email in ["own@example.com", "shared@example.com"] {
+            contact_verification
+                .generate_verification_code(email.to_string(), false)
+                .unwrap();
+        }
+        table("revival_verification")
+            .generate_verification_code(user.email.clone(), false)
+            .unwrap();
+        table("email_verification")
+            .generate_email_change_code(&user.id, "new@example.com".to_string())
+            .unwrap();
+
+        log::debug!("Deleting the account removes all data of the user only");
+        db.delete_account(&user.id, &user.email).unwrap();
+        assert_eq!(db.get_user_by_id(&user.id), Ok(None));
+        assert_eq!(table("settings").get_settings_by_id(&user.id), Ok(None));
+        assert_eq!(table("deadlines").get_deadline(&user.id), Ok(None));
+        assert_eq!(table("devices").get_devices_by_user(&user.id), Ok(vec![]));
+        assert_eq!(contacts.get_contacts_by_user(&user.id), Ok(vec![]));
+        assert_eq!(
+            table("state_history").get_state_history(&user.id),
+            Ok(vec![])
+        );
+        assert_eq!(
+            table("revival_verification").get_verification_by_email(&user.email),
+            Ok(None)
+        );
+        assert_eq!(
+            table("email_verification").get_verification_by_email(&"new@example.com".to_string()),
+            Ok(None)
+        );
+        assert_eq!(
+            contact_verification.get_verification_by_email(&"own@example.com".to_string()),
+            Ok(None)
+        );
+        // The other user's contact still awaits its confirmation
+        assert!(contact_verification
+            .get_verification_by_email(&"shared@example.com".to_string())
+            .unwrap()
+            .is_some());
+        assert!(db.get_user_by_id(&other.id).unwrap().is_some());
+        assert_eq!(contacts.get_contacts_by_user(&other.id).unwrap().len(), 1);
+        assert_eq!(
+            table("devices")
+                .get_devices_by_user(&other.id)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn migrate_empty_database() {
+        let mut connection = rusqlite::Connection::open_in_memory().unwrap();
+        let version = migrate(&mut connection).unwrap();
+        assert_eq!(migrate(&mut connection), Ok(version));
+        let applied: u32 = connection
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(applied, version);
+
+        log::debug!("Refusing a database migrated by a newer server");
+        connection
+            .execute(
+                "INSERT INTO schema_version (version, applied) VALUES ((?), 0)",
+                [version + 1],
+            )
+            .unwrap();
+        assert!(migrate(&mut connection).is_err());
+    }
 }
@@ -0,0 +1,392 @@
+pub mod server_error {
+    use crate::data::data_forms::ResponsePayload;
+
+    use actix_web::{
+        error::{BlockingError, JsonPayloadError, QueryPayloadError},
+        http::StatusCode,
+        HttpResponse, ResponseError,
+    };
+    use serde_derive::Serialize;
+    use std::{fmt, io, num::ParseIntError};
+
+    /// The stable, machine readable code of every failure a client can receive,
+    /// serialized in snake case. Clients may rely on them, so released codes must never change.
+    #[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ErrorCode {
+        // Requests
+        InvalidInput,
+        InvalidPayload,
+        NotFound,
+        InvalidMessageType,
+        TooManyRequests,
+        // Authentication
+        MissingEmail,
+        MissingUserToken,
+        MissingAuthToken,
+        InvalidUserToken,
+        InvalidAuthToken,
+        InvalidCode,
+        TooManyAttempts,
+        EmailAlreadyRegistered,
+        EmailAlreadySubmitted,
+        EmailNotRegistered,
+        // User state
+        Deceased,
+        NotDeceased,
+        InvalidTransition,
+        TimestampInFuture,
+        // Settings
+        InvalidInterval,
+        InvalidEmail,
+        InvalidPause,
+        InvalidPolicy,
+        InvalidRetention,
+        DeletionNotConfirmed,
+        MissingBeacon,
+        BeaconNotFound,
+        // Contacts
+        InvalidContact,
+        InvalidContactId,
+        InvalidContactCode,
+        ContactNotFound,
+        TooManyContacts,
+        InvalidLink,
+        // Devices
+        InvalidDeviceName,
+        InvalidDeviceId,
+        DeviceNotFound,
+        TooManyDevices,
+        // Alarms
+        AlarmNotFound,
+        AlarmNotEscalated,
+        // Server
+        DatabaseBusy,
+        DatabaseError,
+        IoError,
+        InternalError,
+    }
+    impl ErrorCode {
+        /// Every code, in the order they are listed in the catalogue
+        pub const ALL: &'static [ErrorCode] = &[
+            Self::InvalidInput,
+            Self::InvalidPayload,
+            Self::NotFound,
+            Self::InvalidMessageType,
+            Self::TooManyRequests,
+            Self::MissingEmail,
+            Self::MissingUserToken,
+            Self::MissingAuthToken,
+            Self::InvalidUserToken,
+            Self::InvalidAuthToken,
+            Self::InvalidCode,
+            Self::TooManyAttempts,
+            Self::EmailAlreadyRegistered,
+            Self::EmailAlreadySubmitted,
+            Self::EmailNotRegistered,
+            Self::Deceased,
+            Self::NotDeceased,
+            Self::InvalidTransition,
+            Self::TimestampInFuture,
+            Self::InvalidInterval,
+            Self::InvalidEmail,
+            Self::InvalidPause,
+            Self::InvalidPolicy,
+            Self::InvalidRetention,
+            Self::DeletionNotConfirmed,
+            Self::MissingBeacon,
+            Self::BeaconNotFound,
+            Self::InvalidContact,
+            Self::InvalidContactId,
+            Self::InvalidContactCode,
+            Self::ContactNotFound,
+            Self::TooManyContacts,
+            Self::InvalidLink,
+            Self::InvalidDeviceName,
+            Self::InvalidDeviceId,
+            Self::DeviceNotFound,
+            Self::TooManyDevices,
+            Self::AlarmNotFound,
+            Self::AlarmNotEscalated,
+            Self::DatabaseBusy,
+            Self::DatabaseError,
+            Self::IoError,
+            Self::InternalError,
+        ];
+
+        /// The HTTP status every response with this code is sent with
+        pub fn status(&self) -> u16 {
+            match self {
+                Self::InvalidInput
+                | Self::InvalidPayload
+                | Self::MissingEmail
+                | Self::InvalidCode
+                | Self::TimestampInFuture
+                | Self::InvalidInterval
+                | Self::InvalidEmail
+                | Self::InvalidPause
+                | Self::InvalidPolicy
+                | Self::InvalidRetention
+                | Self::DeletionNotConfirmed
+                | Self::MissingBeacon
+                | Self::InvalidContact
+                | Self::InvalidContactId
+                | Self::InvalidContactCode
+                | Self::InvalidLink
+                | Self::InvalidDeviceName
+                | Self::InvalidDeviceId => 400,
+                Self::MissingUserToken
+                | Self::MissingAuthToken
+                | Self::InvalidUserToken
+                | Self::InvalidAuthToken => 401,
+                Self::NotFound
+                | Self::InvalidMessageType
+                | Self::EmailNotRegistered
+                | Self::BeaconNotFound
+                | Self::ContactNotFound
+                | Self::DeviceNotFound
+                | Self::AlarmNotFound => 404,
+                Self::EmailAlreadyRegistered
+                | Self::EmailAlreadySubmitted
+                | Self::Deceased
+                | Self::NotDeceased
+                | Self::InvalidTransition
+                | Self::TooManyContacts
+                | Self::TooManyDevices
+                | Self::AlarmNotEscalated => 409,
+                Self::TooManyRequests | Self::TooManyAttempts => 429,
+                Self::DatabaseError | Self::IoError | Self::InternalError => 500,
+                Self::DatabaseBusy => 503,
+            }
+        }
+        /// The default (english) message of this code, clients may show their own translation
+        pub fn message(&self) -> &'static str {
+            match self {
+                Self::InvalidInput => "Invalid input",
+                Self::InvalidPayload => "Invalid payload",
+                Self::NotFound => "Not Found",
+                Self::InvalidMessageType => "Message Type Invalid",
+                Self::TooManyRequests => "Too many requests",
+                Self::MissingEmail => "No Email provided",
+                Self::MissingUserToken => "No User Token Provided",
+                Self::MissingAuthToken => "No Auth Token Provided",
+                Self::InvalidUserToken => "User Token Invalid",
+                Self::InvalidAuthToken => "Auth Token Invalid",
+                Self::InvalidCode => "Email and/or Code Invalid",
+                Self::TooManyAttempts => "Too many failed attempts, request a new code",
+                Self::EmailAlreadyRegistered => "Email already registered",
+                Self::EmailAlreadySubmitted => "Email already submitted",
+                Self::EmailNotRegistered => "Email not registered",
+                Self::Deceased => "You are marked as deceased",
+                Self::NotDeceased => "You are not marked as deceased",
+                Self::InvalidTransition => "The state of the user can't be changed like this",
+                Self::TimestampInFuture => "Timestamp can't be from the future",
+                Self::InvalidInterval => "No valid interval provided",
+                Self::InvalidEmail => "No valid Email provided",
+                Self::InvalidPause => "No valid pause value provided",
+                Self::InvalidPolicy => "No valid policy provided",
+                Self::InvalidRetention => {
+                    "No valid retention within the limits of the server provided"
+                }
+                Self::DeletionNotConfirmed => "Deletion not confirmed",
+                Self::MissingBeacon => "No beacon provided",
+                Self::BeaconNotFound => "Beacon not found",
+                Self::InvalidContact => "No valid Name and Email provided",
+                Self::InvalidContactId => "No valid contact id provided",
+                Self::InvalidContactCode => "Contact and/or Code Invalid",
+                Self::ContactNotFound => "Contact not found",
+                Self::TooManyContacts => "Too many contacts",
+                Self::InvalidLink => "Link Invalid",
+                Self::InvalidDeviceName => "No valid Name provided",
+                Self::InvalidDeviceId => "No valid device id provided",
+                Self::DeviceNotFound => "Device not found",
+                Self::TooManyDevices => "Too many devices",
+                Self::AlarmNotFound => "Alarm not found",
+                Self::AlarmNotEscalated => {
+                    "The alarm can't be acknowledged before the contacts were notified"
+                }
+                Self::DatabaseBusy => "Service Unavailable\nPlease try again later",
+                Self::DatabaseError | Self::IoError | Self::InternalError => {
+                    "Internal Server Error\nPlease try again later"
+                }
+            }
+        }
+    }
+
+    /// An entry of the error catalogue, see `ErrorCode`
+    #[derive(Serialize, Debug)]
+    pub struct ErrorDescription {
+        pub code: ErrorCode,
+        pub status: u16,
+        pub message: &'static str,
+    }
+    impl From<ErrorCode> for ErrorDescription {
+        fn from(code: ErrorCode) -> Self {
+            Self {
+                code,
+                status: code.status(),
+                message: code.message(),
+            }
+        }
+    }
+
+    /// Failures while handling a request. Instead of panicking the worker, they are answered
+    /// with a `ResponsePayload` carrying a stable error code clients may rely on.
+    #[derive(Debug)]
+    pub enum ServerError {
+        /// A part of the request couldn't be parsed, like a code in the path which isn't a number
+        Parse(String),
+        /// The body of the request isn't a valid payload
+        Json(String),
+        /// The database is locked or no connection became available in time
+        DatabaseBusy(rusqlite::Error),
+        /// Any other database failure
+        Database(rusqlite::Error),
+        /// Reading or writing a file failed
+        Io(io::Error),
+        /// The blocking task was dropped before it finished
+        Canceled,
+    }
+    impl ServerError {
+        /// Returns the stable, machine readable code of this error
+        pub fn code(&self) -> ErrorCode {
+            match self {
+                Self::Parse(_) => ErrorCode::InvalidInput,
+                Self::Json(_) => ErrorCode::InvalidPayload,
+                Self::DatabaseBusy(_) => ErrorCode::DatabaseBusy,
+                Self::Database(_) => ErrorCode::DatabaseError,
+                Self::Io(_) => ErrorCode::IoError,
+                Self::Canceled => ErrorCode::InternalError,
+            }
+        }
+    }
+    impl fmt::Display for ServerError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::Parse(err) => write!(f, "Invalid input: {}", err),
+                Self::Json(err) => write!(f, "Invalid payload: {}", err),
+                Self::DatabaseBusy(err) => write!(f, "Database busy: {}", err),
+                Self::Database(err) => write!(f, "Database error: {}", err),
+                Self::Io(err) => write!(f, "IO error: {}", err),
+                Self::Canceled => write!(f, "The blocking task was canceled"),
+            }
+        }
+    }
+    impl std::error::Error for ServerError {}
+
+    impl From<rusqlite::Error> for ServerError {
+        fn from(err: rusqlite::Error) -> Self {
+            match &err {
+                // A pool which ran out of connections reports that it can't open one
+                rusqlite::Error::SqliteFailure(failure, _)
+                    if matches!(
+                        failure.code,
+                        rusqlite::ErrorCode::DatabaseBusy
+                            | rusqlite::ErrorCode::DatabaseLocked
+                            | rusqlite::ErrorCode::CannotOpen
+                    ) =>
+                {
+                    Self::DatabaseBusy(err)
+                }
+                _ => Self::Database(err),
+            }
+        }
+    }
+    impl From<io::Error> for ServerError {
+        fn from(err: io::Error) -> Self {
+            Self::Io(err)
+        }
+    }
+    impl From<ParseIntError> for ServerError {
+        fn from(err: ParseIntError) -> Self {
+            Self::Parse(err.to_string())
+        }
+    }
+    impl From<serde_json::Error> for ServerError {
+        fn from(err: serde_json::Error) -> Self {
+            Self::Json(err.to_string())
+        }
+    }
+    impl From<JsonPayloadError> for ServerError {
+        fn from(err: JsonPayloadError) -> Self {
+            Self::Json(err.to_string())
+        }
+    }
+    impl From<QueryPayloadError> for ServerError {
+        fn from(err: QueryPayloadError) -> Self {
+            Self::Parse(err.to_string())
+        }
+    }
+    impl<E> From<BlockingError<E>> for ServerError
+    where
+        E: Into<ServerError> + fmt::Debug,
+    {
+        fn from(err: BlockingError<E>) -> Self {
+            match err {
+                BlockingError::Error(err) => err.into(),
+                BlockingError::Canceled => Self::Canceled,
+            }
+        }
+    }
+
+    impl ResponseError for ServerError {
+        fn status_code(&self) -> StatusCode {
+            StatusCode::from_u16(self.code().status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        fn error_response(&self) -> HttpResponse {
+            // Internal details end up in the server log only
+            let payload = match self {
+                Self::Parse(_) | Self::Json(_) => {
+                    ResponsePayload::error_with_message(self.code(), self.to_string())
+                }
+                _ => ResponsePayload::error(self.code()),
+            };
+            HttpResponse::build(self.status_code()).json(payload)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::server_error::{ErrorCode, ServerError};
+
+    use actix_web::{http::StatusCode, ResponseError};
+    use rusqlite::ffi;
+    use std::collections::HashSet;
+
+    #[test]
+    fn error_status_and_code() {
+        let err = ServerError::from("abc".parse::<u64>().unwrap_err());
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+
+        let busy = ServerError::from(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_BUSY),
+            None,
+        ));
+        assert_eq!(busy.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(busy.code(), ErrorCode::DatabaseBusy);
+
+        let db = ServerError::from(rusqlite::Error::QueryReturnedNoRows);
+        assert_eq!(db.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(db.code(), ErrorCode::DatabaseError);
+
+        let json = ServerError::from(serde_json::from_str::<u32>("{").unwrap_err());
+        assert_eq!(json.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(json.code(), ErrorCode::InvalidPayload);
+    }
+
+    #[test]
+    fn error_catalogue() {
+        let mut names = HashSet::new();
+        for code in ErrorCode::ALL {
+            assert!(code.status() >= 400);
+            assert!(!code.message().is_empty());
+            assert!(names.insert(serde_json::to_string(code).unwrap()));
+        }
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::InvalidAuthToken).unwrap(),
+            "\"invalid_auth_token\""
+        );
+    }
+}
@@ -0,0 +1,251 @@
+pub mod smtp_handler {
+    use crate::data::data_forms::{ConfigSmtp, SmtpSecurity};
+
+    use chrono::{TimeZone, Utc};
+    use lettre::{
+        address::AddressError,
+        message::{header::ContentType, Mailbox},
+        transport::smtp::{self, authentication::Credentials},
+        Message, SmtpTransport, Transport,
+    };
+    use std::{collections::HashMap, fmt};
+
+    /// Everything that can go wrong while composing or sending an email
+    #[derive(Debug)]
+    pub enum MailError {
+        Address(AddressError),
+        Message(lettre::error::Error),
+        Transport(smtp::Error),
+        Template(std::io::Error),
+    }
+    impl fmt::Display for MailError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                MailError::Address(e) => write!(f, "Invalid email address: {}", e),
+                MailError::Message(e) => write!(f, "Failed to build email: {}", e),
+                MailError::Transport(e) => write!(f, "Failed to send email: {}", e),
+                MailError::Template(e) => write!(f, "Failed to read email template: {}", e),
+            }
+        }
+    }
+    impl std::error::Error for MailError {}
+    impl From<AddressError> for MailError {
+        fn from(e: AddressError) -> Self {
+            MailError::Address(e)
+        }
+    }
+    impl From<lettre::error::Error> for MailError {
+        fn from(e: lettre::error::Error) -> Self {
+            MailError::Message(e)
+        }
+    }
+    impl From<smtp::Error> for MailError {
+        fn from(e: smtp::Error) -> Self {
+            MailError::Transport(e)
+        }
+    }
+    impl From<std::io::Error> for MailError {
+        fn from(e: std::io::Error) -> Self {
+            MailError::Template(e)
+        }
+    }
+
+    /// The outbound mail subsystem, holds the SMTP connection (pool) and the sender addresses
+    #[derive(Clone, Debug)]
+    pub struct Mailer {
+        transport: SmtpTransport,
+        sender: Mailbox,
+        admin: Option<Mailbox>,
+    }
+    impl Mailer {
+        /// Create a new Mailer from the SMTP config. This does not connect to the server yet,
+        /// it only fails if the configured addresses or the server are invalid.
+        pub fn from_config(cfg: &ConfigSmtp) -> Result<Self, MailError> {
+            let builder = match cfg.smtp_security {
+                SmtpSecurity::None => SmtpTransport::builder_dangerous(&cfg.smtp_server),
+                SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&cfg.smtp_server)?,
+                SmtpSecurity::Tls => SmtpTransport::relay(&cfg.smtp_server)?,
+            }
+            .port(cfg.smtp_port);
+            // Only authenticate if a username is configured, local relays usually don't need it
+            let builder = if cfg.smtp_username.is_empty() {
+                builder
+            } else {
+                builder.credentials(Credentials::new(
+                    cfg.smtp_username.clone(),
+                    cfg.smtp_password.clone(),
+                ))
+            };
+
+            Ok(Self {
+                transport: builder.build(),
+                sender: cfg.sender_mail_addr.parse()?,
+                admin: match &cfg.admin_mail_addr {
+                    Some(addr) => Some(addr.parse()?),
+                    None => None,
+                },
+            })
+        }
+        /// Send a HTML email with the given subject and body to the given address.
+        /// If `cc_admin` is set and an admin address is configured, the admin is put in CC.
+        pub fn send(
+            &self,
+            to: &str,
+            cc_admin: bool,
+            subject: &str,
+            body: String,
+        ) -> Result<(), MailError> {
+            let mut builder = Message::builder()
+                .from(self.sender.clone())
+                .to(to.parse()?)
+                .subject(subject)
+                .header(ContentType::TEXT_HTML);
+            if let (true, Some(admin)) = (cc_admin, &self.admin) {
+                builder = builder.cc(admin.clone());
+            }
+            let message = builder.body(body)?;
+
+            self.transport.send(&message)?;
+            Ok(())
+        }
+        /// Send a HTML email with the given subject and body to the admin.
+        /// Returns false if no admin address is configured.
+        pub fn send_admin(&self, subject: &str, body: String) -> Result<bool, MailError> {
+            let admin = match &self.admin {
+                Some(admin) => admin.email.to_string(),
+                None => return Ok(false),
+            };
+            self.send(&admin, false, subject, body)?;
+            Ok(true)
+        }
+    }
+
+    /// Escapes the characters that have a meaning in HTML
+    fn escape_html(value: &str) -> String {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    /// Renders a template by replacing every `{{key}}` with the HTML escaped value of that key.
+    /// Placeholders without a value are left untouched.
+    pub fn render_template(template: &str, values: &HashMap<&str, String>) -> String {
+        let mut rendered = template.to_string();
+        for (key, value) in values {
+            rendered = rendered.replace(&format!("{{{{{}}}}}", key), &escape_html(value));
+        }
+        rendered
+    }
+
+    /// Formats the given unix timestamp for use in a template
+    pub fn format_timestamp(timestamp: u32) -> String {
+        Utc.timestamp_opt(timestamp as i64, 0)
+            .single()
+            .map(|t| t.to_rfc2822())
+            .unwrap_or_default()
+    }
+
+    /// Reads the template at the given path and renders it, see `render_template`
+    pub fn render_template_file(
+        path: &str,
+        values: &HashMap<&str, String>,
+    ) -> Result<String, std::io::Error> {
+        Ok(render_template(&std::fs::read_to_string(path)?, values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::data_forms::{ConfigSmtp, SmtpSecurity};
+    use crate::mail_handler::smtp_handler::{render_template, Mailer};
+
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    /// Spawns a minimal SMTP sink accepting a single message, returns the port it listens on
+    /// and a receiver yielding the raw message data.
+    fn spawn_smtp_sink() -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 localhost ESMTP sink\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_uppercase();
+                if command.starts_with("EHLO") || command.starts_with("HELO") {
+                    writer.write_all(b"250 localhost\r\n").unwrap();
+                } else if command == "DATA" {
+                    writer.write_all(b"354 Go ahead\r\n").unwrap();
+                    let mut data = String::new();
+                    loop {
+                        let mut data_line = String::new();
+                        reader.read_line(&mut data_line).unwrap();
+                        if data_line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&data_line);
+                    }
+                    writer.write_all(b"250 Ok\r\n").unwrap();
+                    tx.send(data).unwrap();
+                    return;
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    return;
+                } else {
+                    writer.write_all(b"250 Ok\r\n").unwrap();
+                }
+                line.clear();
+            }
+        });
+        (port, rx)
+    }
+
+    #[test]
+    fn render_templates() {
+        let mut values = HashMap::new();
+        values.insert("email", "<foo@example.com>".to_string());
+        assert_eq!(
+            render_template("Hello {{email}}, {{unknown}}", &values),
+            "Hello &lt;foo@example.com&gt;, {{unknown}}"
+        );
+    }
+    #[test]
+    fn send_to_local_sink() {
+        let (port, rx) = spawn_smtp_sink();
+        let cfg = ConfigSmtp {
+            admin_mail_addr: Some("admin@example.com".to_string()),
+            sender_mail_addr: "dmnb@example.com".to_string(),
+            smtp_server: "127.0.0.1".to_string(),
+            smtp_port: port,
+            smtp_security: SmtpSecurity::None,
+            smtp_username: "".to_string(),
+            smtp_password: "".to_string(),
+        };
+        let mailer = Mailer::from_config(&cfg).unwrap();
+        mailer
+            .send(
+                "foo@example.com",
+                true,
+                "Test Subject",
+                "<p>Test Body</p>".to_string(),
+            )
+            .unwrap();
+
+        let data = rx.recv().unwrap();
+        assert!(data.contains("To: foo@example.com"));
+        assert!(data.contains("Cc: admin@example.com"));
+        assert!(data.contains("Subject: Test Subject"));
+        assert!(data.contains("<p>Test Body</p>"));
+    }
+}
@@ -1,35 +1,68 @@
+mod alarm;
+pub use crate::alarm::alarm_engine;
+
+mod audit;
+pub use crate::audit::audit_log;
+
 mod data;
 pub use crate::data::data_forms;
 
+mod error;
+pub use crate::error::server_error;
+
 mod request_handler;
 pub use crate::request_handler::handler;
 
 mod data_handler;
 pub use crate::data_handler::sqlite_handler;
 
+mod mail_handler;
+pub use crate::mail_handler::smtp_handler;
+
+mod signature_handler;
+pub use crate::signature_handler::signing;
+
+mod scheduler;
+pub use crate::scheduler::deadline_scheduler;
+
+mod rate_limiter;
+pub use crate::rate_limiter::rate_limiting;
+
 mod state_engine;
 pub use crate::state_engine::state_functions;
 
 use chrono::{self, Local};
-use confy;
 use env_logger::Builder;
 use log::LevelFilter;
-use rand::Rng;
 use std::{
-    collections::HashMap,
     convert::TryInto,
     io::Write,
     path::Path,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::mpsc,
     thread,
+    time::{Duration, Instant},
 };
 
+/// The longest time between two escalation ticks. Alarms are escalated once their next stage is
+/// due, in between users marked as deceased otherwise are picked up and failed stages retried.
+const ESCALATION_INTERVAL: Duration = Duration::from_secs(60);
+/// How often expired verification codes are deleted
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The current unix timestamp
+fn now() -> u32 {
+    chrono::offset::Utc::now()
+        .timestamp()
+        .try_into()
+        .expect("Time went backwards")
+}
+
 fn init() -> data_forms::ConfigMain {
     log::info!("Initializing DMNB Server...");
     // Read config
     let cfg: data_forms::ConfigMain = confy::load_path(Path::new("./rsc/dmnb.config")).unwrap();
     log::debug!("Read the config successfully");
-    return cfg;
+    cfg
 }
 
 fn main() {
@@ -52,11 +85,38 @@ fn main() {
     let cfg = init();
     let cfg_cloned = cfg.clone();
     let database_path = cfg.file_locations.database_path.clone();
+    let mailer =
+        smtp_handler::Mailer::from_config(&cfg.smtp_config).expect("Failed to set up the mailer");
+    let mailer_cloned = mailer.clone();
+    let key = signing::ServerKey::load_or_create(&cfg.file_locations.server_key)
+        .expect("Failed to load the server key");
+    let key_cloned = key.clone();
+    let schema_version =
+        sqlite_handler::migrate_database(&database_path).expect("Failed to migrate the database");
+    log::debug!("Database schema is at version {}", schema_version);
+    let pool = sqlite_handler::create_pool(&database_path).expect("Failed to connect to database");
+    match sqlite_handler::DatabaseState::from_pool(&pool, "users")
+        .map_err(|err| err.into())
+        .and_then(|db| audit_log::import_log_files(&db, &cfg.file_locations.log_folder, &key))
+    {
+        Ok(0) => (),
+        Ok(imported) => log::info!("Imported {} audit logs into the database", imported),
+        Err(err) => log::error!("Failed to import audit logs!\n: {}", err),
+    }
     log::info!("Starting DMNB Server...");
 
+    // Audit logs are only ever appended to, they are shrunk to their retention, purged
+    // after the archive period and their hash chains are signed in the background
+    audit_log::spawn_maintainer(
+        database_path.clone(),
+        key.clone(),
+        cfg.audit_config.clone(),
+        audit_log::MAINTENANCE_INTERVAL,
+    );
+
     // Spawn Thread to check whenever a message was expected and received, and delete outtimed user-settings-token
     // aka. the 'invalid entry collector thread'
-    let (tx, rx): (Sender<(String, u32)>, Receiver<(String, u32)>) = mpsc::channel();
+    let (tx, rx) = mpsc::channel::<(String, u32)>();
     thread::spawn(move || {
         // Get the database connection
         let db = sqlite_handler::DatabaseState::init(database_path.to_string())
@@ -66,66 +126,135 @@ fn main() {
             "verification".to_string(),
         )
         .expect("Failed to connect to database");
-        // Create tables if not already existent
-        db.create_table_for_user()
-            .expect("Failed to create table for users");
-        verify_db
-            .create_table_for_verification()
-            .expect("Failed to create table for verification");
-
-        let mut rng = rand::thread_rng();
-        let mut alltimes: HashMap<String, u32> = HashMap::new();
+        let contact_verify_db = sqlite_handler::DatabaseState::init_with_table_name(
+            database_path.to_string(),
+            "contact_verification".to_string(),
+        )
+        .expect("Failed to connect to database");
+        let revival_verify_db = sqlite_handler::DatabaseState::init_with_table_name(
+            database_path.to_string(),
+            "revival_verification".to_string(),
+        )
+        .expect("Failed to connect to database");
+        let email_verify_db = sqlite_handler::DatabaseState::init_with_table_name(
+            database_path.to_string(),
+            "email_verification".to_string(),
+        )
+        .expect("Failed to connect to database");
+        let deadline_db = sqlite_handler::DatabaseState::init_with_table_name(
+            database_path.to_string(),
+            "deadlines".to_string(),
+        )
+        .expect("Failed to connect to database");
+        // Restore the deadlines of the last run, so no user stops being watched after a restart
+        let mut deadlines = deadline_scheduler::DeadlineScheduler::from_deadlines(
+            deadline_db
+                .get_all_deadlines()
+                .expect("Failed to load deadlines"),
+        );
+        log::debug!("Loaded {} deadlines from the database", deadlines.len());
+        let engine = alarm_engine::AlarmEngine::init(database_path.to_string(), mailer, key, cfg)
+            .expect("Failed to set up the alarm engine");
+        // Escalate alarms which became due while the server was down right away
+        let mut escalate_pending = true;
+        let mut next_escalation = Instant::now();
+        let mut next_cleanup = Instant::now();
         log::debug!("Starting the invalid entry collector loop...");
         loop {
-            // Users System
-            loop {
-                match rx.try_recv() {
-                    Ok(val) => {
-                        alltimes.insert(val.0, val.1);
+            // Sleep until the next deadline expires, the next escalation is due or a new
+            // deadline arrives. Deadlines expire once their second has passed.
+            let mut timeout = next_escalation.saturating_duration_since(Instant::now());
+            if let Some(expires) = deadlines.next_expiry() {
+                let until_expiry = Duration::from_secs((expires + 1).saturating_sub(now()).into());
+                timeout = timeout.min(until_expiry);
+            }
+            let mut received = match rx.recv_timeout(timeout) {
+                Ok(val) => vec![val],
+                Err(mpsc::RecvTimeoutError::Timeout) => vec![],
+                Err(err @ mpsc::RecvTimeoutError::Disconnected) => {
+                    // The request handler stopped, nobody can check in anymore
+                    log::error!("{}", err);
+                    break;
+                }
+            };
+            // The deadlines are already stored by the request handler, per beacon
+            received.extend(rx.try_iter());
+            for (id, expires) in received {
+                deadlines.set(id, expires);
+            }
+
+            let current_time = now();
+            for id in deadlines.pop_expired(current_time) {
+                // A beacon may have checked in since, or its deadline was forgotten
+                match deadline_db.get_deadline(&id) {
+                    Ok(Some(expires)) if expires >= current_time => {
+                        deadlines.set(id, expires);
+                        continue;
                     }
-                    Err(err) => match err {
-                        mpsc::TryRecvError::Empty => break,
-                        mpsc::TryRecvError::Disconnected => {
-                            log::error!("{}", err);
-                            break;
-                        }
-                    },
+                    Ok(Some(_)) => (),
+                    Ok(None) => continue,
+                    Err(e) => log::error!("Failed to check deadline of outtimed user!\n: {}", e),
+                }
+                if let Err(e) = deadline_db.delete_deadline(&id) {
+                    log::error!("Failed to delete deadline of outtimed user!\n: {}", e);
+                }
+                let user = match db.get_user_by_id(&id) {
+                    Ok(Some(user)) => user,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::error!("Failed to get outtimed user!\n: {}", e);
+                        continue;
+                    }
+                };
+                // Paused users are not alarmed, their deadline is just dropped
+                if user.state == data_forms::UserState::Paused {
+                    log::debug!("USER {} outtimed while paused", id);
+                    continue;
+                }
+                // If yes, raise an alarm for that user
+                if let Err(e) = engine.raise(&user, current_time) {
+                    log::error!("Failed to raise alarm for outtimed user!\n: {}", e);
+                    continue;
                 };
+                log::debug!("USER {} just outtimed and was marked as `overdue`", id);
+                escalate_pending = true;
             }
-            let current_time: u32 = chrono::offset::Utc::now()
-                .timestamp()
-                .try_into()
-                .expect("Time went backwards");
-            for (id, time) in alltimes.clone().iter() {
-                // Check if user is outtimed
-                if &current_time > time {
-                    // If yes, update the state of that user
-                    if let Err(e) = db.update_state_user(&id.to_string(), 10) {
-                        log::error!("Failed to update state of outtimed user!\n: {}", e);
-                    };
-                    alltimes.remove(id).unwrap();
-                    // Log that the user has been set to deceased
-                    if let Ok(Some(user)) = db.get_user_by_id(id) {
-                        state_functions::custom_log_line(
-                            &user,
-                            "User was marked as deceased due to timeout".to_string(),
-                            &cfg.file_locations.log_folder,
-                        )
-                        .unwrap();
+            // Alarms are escalated right after a user outtimed and otherwise once their next
+            // stage is due, but at least once every interval
+            if escalate_pending || Instant::now() >= next_escalation {
+                let mut until_escalation = ESCALATION_INTERVAL;
+                match engine.tick(current_time) {
+                    Ok(Some(due)) => {
+                        let until_due = due.saturating_sub(current_time).max(1);
+                        until_escalation =
+                            until_escalation.min(Duration::from_secs(until_due.into()));
                     }
-                    log::debug!("USER {} just outtimed and was marked as `deceased`", id);
+                    Ok(None) => (),
+                    Err(e) => log::error!("Failed to escalate alarms!\n: {}", e),
                 }
+                escalate_pending = false;
+                next_escalation = Instant::now() + until_escalation;
             }
-            // User-Settings Tokens will only be deleted with a propability of 1:5000 to not overload the
-            // database (might be wrong reasoning). In any case expiration should be checked when
-            // the key is submitted by the user.
-            if rng.gen_range(0..5000) == 1 {
+            // Expiration is checked when a code is submitted anyway,
+            // expired codes are only deleted from time to time to keep the tables small
+            if Instant::now() >= next_cleanup {
                 if let Err(e) = verify_db.delete_outtimed_verifications() {
                     log::error!("Failed to update verifications!\n: {}", e);
                 }
+                if let Err(e) = contact_verify_db.delete_outtimed_verifications() {
+                    log::error!("Failed to update contact verifications!\n: {}", e);
+                }
+                if let Err(e) = revival_verify_db.delete_outtimed_verifications() {
+                    log::error!("Failed to update revival verifications!\n: {}", e);
+                }
+                if let Err(e) = email_verify_db.delete_outtimed_verifications() {
+                    log::error!("Failed to update email verifications!\n: {}", e);
+                }
+                next_cleanup = Instant::now() + CLEANUP_INTERVAL;
             }
         }
     });
     log::debug!("Starting the request handler...");
-    handler::run(cfg_cloned, tx).unwrap_or_else(|err| log::error!("{}", err));
+    handler::run(cfg_cloned, tx, mailer_cloned, key_cloned, pool)
+        .unwrap_or_else(|err| log::error!("{}", err));
 }
@@ -0,0 +1,287 @@
+pub mod rate_limiting {
+    use crate::data::data_forms::{ConfigRateLimit, ResponsePayload};
+    use crate::error::server_error::ErrorCode;
+
+    use actix_web::{
+        dev::{Service, ServiceRequest, ServiceResponse, Transform},
+        http::StatusCode,
+        Error, HttpResponse, ResponseError,
+    };
+    use percent_encoding::percent_decode_str;
+    use std::{
+        collections::HashMap,
+        fmt,
+        future::{ready, Future, Ready},
+        net::SocketAddr,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
+
+    /// The endpoints which are rate limited, requests to other paths pass through
+    const LIMITED_PATHS: &[&str] = &[
+        "/api/register",
+        "/api/verify/",
+        "/api/revive",
+        "/api/email/",
+    ];
+    /// Expired counters are only dropped once this many keys are tracked
+    const CLEANUP_THRESHOLD: usize = 4096;
+
+    /// The request counters per key and when expired counters were dropped the last time
+    #[derive(Debug)]
+    struct Counters {
+        counts: HashMap<String, (Instant, u32)>,
+        last_cleanup: Instant,
+    }
+
+    /// Counts the requests per key (like an IP or an email) in fixed windows
+    #[derive(Debug)]
+    pub struct RateLimiter {
+        window: Duration,
+        counters: Mutex<Counters>,
+    }
+    impl RateLimiter {
+        /// Create a new rate limiter resetting its counters after the given window
+        pub fn new(window: Duration) -> Self {
+            Self {
+                window,
+                counters: Mutex::new(Counters {
+                    counts: HashMap::new(),
+                    last_cleanup: Instant::now(),
+                }),
+            }
+        }
+        /// The number of keys currently tracked
+        #[cfg(test)]
+        pub fn tracked_keys(&self) -> usize {
+            self.counters
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .counts
+                .len()
+        }
+        /// Counts a request of the given key. If the key already made `limit` requests in the
+        /// current window, the request is not counted and the time until the window ends is
+        /// returned instead.
+        pub fn check(&self, key: &str, limit: u32, now: Instant) -> Result<(), Duration> {
+            let mut counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+            // Counters only expire once their window ended, so it's enough to drop
+            // them once per window instead of on every request
+            if counters.counts.len() >= CLEANUP_THRESHOLD
+                && now.saturating_duration_since(counters.last_cleanup) >= self.window
+            {
+                let window = self.window;
+                counters
+                    .counts
+                    .retain(|_, (start, _)| now.saturating_duration_since(*start) < window);
+                counters.last_cleanup = now;
+            }
+            let (start, count) = counters.counts.entry(key.to_string()).or_insert((now, 0));
+            if now.saturating_duration_since(*start) >= self.window {
+                *start = now;
+                *count = 0;
+            }
+            if *count >= limit {
+                return Err(self.window - now.saturating_duration_since(*start));
+            }
+            *count += 1;
+            Ok(())
+        }
+    }
+
+    /// A request was rejected by the rate limiter, answered with 429 and a Retry-After header
+    #[derive(Debug)]
+    pub struct TooManyRequests {
+        retry_after: Duration,
+    }
+    impl fmt::Display for TooManyRequests {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "Too many requests, retry in {} seconds",
+                self.retry_after_secs()
+            )
+        }
+    }
+    impl TooManyRequests {
+        /// The seconds until the client may try again, rounded up
+        fn retry_after_secs(&self) -> u64 {
+            self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0)
+        }
+    }
+    impl ResponseError for TooManyRequests {
+        fn status_code(&self) -> StatusCode {
+            StatusCode::TOO_MANY_REQUESTS
+        }
+        fn error_response(&self) -> HttpResponse {
+            HttpResponse::TooManyRequests()
+                .header("Retry-After", self.retry_after_secs().to_string())
+                .json(ResponsePayload::error(ErrorCode::TooManyRequests))
+        }
+    }
+
+    /// Middleware limiting the requests to the register, verify and revive endpoints
+    /// per client IP and per email, see `ConfigRateLimit`
+    #[derive(Debug, Clone)]
+    pub struct RateLimit {
+        limiter: Arc<RateLimiter>,
+        cfg: ConfigRateLimit,
+    }
+    impl RateLimit {
+        /// Create the middleware, it's shared by all workers of the server
+        pub fn new(cfg: ConfigRateLimit) -> Self {
+            Self {
+                limiter: Arc::new(RateLimiter::new(Duration::from_secs(cfg.window.into()))),
+                cfg,
+            }
+        }
+        /// Counts the given request, returns an error if it exceeds a limit
+        fn check(&self, req: &ServiceRequest) -> Result<(), TooManyRequests> {
+            let path = req.path();
+            if !LIMITED_PATHS
+                .iter()
+                .any(|limited| path.starts_with(limited))
+            {
+                return Ok(());
+            }
+            let now = Instant::now();
+            let ip = if self.cfg.trust_forwarded_for {
+                req.connection_info().realip_remote_addr().map(|addr| {
+                    addr.parse::<SocketAddr>()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_else(|_| addr.to_string())
+                })
+            } else {
+                req.peer_addr().map(|addr| addr.ip().to_string())
+            };
+            if let Some(ip) = ip {
+                self.limiter
+                    .check(&format!("ip:{}", ip), self.cfg.requests_per_ip, now)
+                    .map_err(|retry_after| TooManyRequests { retry_after })?;
+            }
+            if let Some(email) = email_of(req) {
+                self.limiter
+                    .check(
+                        &format!("email:{}", email),
+                        self.cfg.requests_per_email,
+                        now,
+                    )
+                    .map_err(|retry_after| TooManyRequests { retry_after })?;
+            }
+            Ok(())
+        }
+    }
+    /// Returns the email a request concerns, given in the Email header
+    /// or in the path (`/api/verify/{email}/{code}`)
+    fn email_of(req: &ServiceRequest) -> Option<String> {
+        let email = match req.headers().get("Email") {
+            Some(val) => val.to_str().ok()?.to_string(),
+            None => {
+                let segment = req.path().trim_start_matches('/').split('/').nth(2)?;
+                percent_decode_str(segment).decode_utf8().ok()?.to_string()
+            }
+        };
+        Some(email.trim().to_lowercase())
+    }
+
+    impl<S, B> Transform<S> for RateLimit
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+    {
+        type Request = ServiceRequest;
+        type Response = ServiceResponse<B>;
+        type Error = Error;
+        type InitError = ();
+        type Transform = RateLimitMiddleware<S>;
+        type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+        fn new_transform(&self, service: S) -> Self::Future {
+            ready(Ok(RateLimitMiddleware {
+                service,
+                rate_limit: self.clone(),
+            }))
+        }
+    }
+
+    /// The rate limiting service wrapping the app, see `RateLimit`
+    pub struct RateLimitMiddleware<S> {
+        service: S,
+        rate_limit: RateLimit,
+    }
+    impl<S, B> Service for RateLimitMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+    {
+        type Request = ServiceRequest;
+        type Response = ServiceResponse<B>;
+        type Error = Error;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.service.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: ServiceRequest) -> Self::Future {
+            if let Err(err) = self.rate_limit.check(&req) {
+                log::debug!("{} rate limited: {}", req.path(), err);
+                return Box::pin(ready(Err(err.into())));
+            }
+            Box::pin(self.service.call(req))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limiter::rate_limiting::RateLimiter;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn limit_per_window() {
+        let limiter = RateLimiter::new(Duration::from_secs(60));
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check("foo", 3, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.check("foo", 3, start + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+        // Other keys are counted separately
+        assert_eq!(limiter.check("bar", 3, start), Ok(()));
+        // The counter is reset once the window ended
+        assert_eq!(
+            limiter.check("foo", 3, start + Duration::from_secs(60)),
+            Ok(())
+        );
+    }
+    #[test]
+    fn drop_expired_counters() {
+        let limiter = RateLimiter::new(Duration::from_secs(60));
+        let start = Instant::now();
+        for i in 0..4096 {
+            limiter.check(&i.to_string(), 3, start).unwrap();
+        }
+        let at = |secs| start + Duration::from_secs(secs);
+        limiter.check("early", 3, at(30)).unwrap();
+        // Once the window ended the expired counters are dropped
+        limiter.check("foo", 3, at(60)).unwrap();
+        assert_eq!(limiter.tracked_keys(), 2);
+
+        log::debug!("Expired counters are dropped at most once per window");
+        for i in 0..4096 {
+            limiter.check(&i.to_string(), 3, at(61)).unwrap();
+        }
+        limiter.check("bar", 3, at(100)).unwrap();
+        assert_eq!(limiter.tracked_keys(), 4099);
+        limiter.check("baz", 3, at(121)).unwrap();
+        assert_eq!(limiter.tracked_keys(), 2);
+    }
+}
@@ -1,285 +1,909 @@
 pub mod handler {
     use crate::data::data_forms::{
-        ConfigMain, RequestPayload, ResponsePayload, ResponsePayloadTypes,
+        Alarm, AuditQuery, ConfigMain, Credentials, Device, RequestPayload, ResponsePayload,
+        ResponsePayloadTypes, User, UserSettings,
     };
-    use crate::data_handler::sqlite_handler::DatabaseState;
+    use crate::data_handler::sqlite_handler::{DatabaseState, DbPool};
+    use crate::error::server_error::{ErrorCode, ServerError};
+    use crate::mail_handler::smtp_handler::{self, Mailer};
+    use crate::rate_limiter::rate_limiting::RateLimit;
+    use crate::signature_handler::signing::ServerKey;
     use crate::state_engine::state_functions;
 
     use actix_web::{
-        get, middleware::Logger, post, web, App, HttpRequest, HttpResponse, HttpServer,
+        get, http::StatusCode, middleware::Logger, post, web, App, HttpRequest, HttpResponse,
+        HttpServer, ResponseError,
     };
     use chrono;
     use rand::Rng;
-    use std::{convert::TryInto, sync::mpsc::Sender};
+    use std::{collections::HashMap, convert::TryInto, sync::mpsc::Sender};
+
+    /// Turns the given payload into a JSON response with the status code of the payload
+    fn respond(payload: ResponsePayload) -> HttpResponse {
+        HttpResponse::build(
+            StatusCode::from_u16(payload.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        )
+        .json(payload)
+    }
+    /// Runs the given function on the blocking thread pool, so the database calls it makes
+    /// don't stall the async workers. Errors it returns are answered with their error payload.
+    async fn blocking<F>(f: F) -> HttpResponse
+    where
+        F: FnOnce() -> Result<ResponsePayload, ServerError> + Send + 'static,
+    {
+        match web::block(f).await {
+            Ok(payload) => respond(payload),
+            Err(err) => {
+                let err = ServerError::from(err);
+                log::error!("{}", err);
+                err.error_response()
+            }
+        }
+    }
+    /// Returns the shared state of the app
+    fn app_state(req: &HttpRequest) -> web::Data<AppState> {
+        req.app_data::<web::Data<AppState>>().unwrap().clone()
+    }
+    /// Returns the user the given token belongs to, along with the device if it's the token
+    /// of a device. The time a device was last seen is updated on the way.
+    fn authenticate(
+        pool: &DbPool,
+        db: &DatabaseState,
+        token: &str,
+    ) -> Result<Option<(User, Option<Device>)>, rusqlite::Error> {
+        if let Some(user) = db.get_user_by_token(token)? {
+            return Ok(Some((user, None)));
+        }
+        let device_db = DatabaseState::from_pool(pool, "devices")?;
+        let device = match device_db.get_device_by_token(token)? {
+            Some(device) => device,
+            None => return Ok(None),
+        };
+        let user = match db.get_user_by_id(&device.user_id)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let now = chrono::Utc::now()
+            .timestamp()
+            .try_into()
+            .expect("Time went backwards");
+        device_db.touch_device(device.id, now)?;
+        Ok(Some((user, Some(device))))
+    }
 
     // Serve Register API
     #[post("/api/register")]
     async fn register(req: HttpRequest) -> HttpResponse {
         let email = match match req.headers().get("Email") {
             Some(val) => val.to_str().ok(),
-            None => {
-                return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                    400,
-                    "No Email provided",
-                ))
-            }
+            None => return respond(ResponsePayload::error(ErrorCode::MissingEmail)),
         } {
-            Some(email) => email,
-            None => {
-                return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                    400,
-                    "No Email provided",
-                ))
-            }
+            Some(email) => email.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingEmail)),
         };
-        // Check if Email is already in user database
-        let db = DatabaseState::init(req.app_data::<AppState>().unwrap().db_path.clone())
-            .expect("Failed to connect to database!");
-        if let Ok(user) = db.get_user_by_email(&email.to_string()) {
-            if user.is_some() {
-                return HttpResponse::Conflict().json(ResponsePayload::new_static_message(
-                    409,
-                    "Email already registered",
-                ));
+        let state = app_state(&req);
+        blocking(move || {
+            // Check if Email is already in user database, users whose token was revoked
+            // verify their email again to get a new one
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            if let Ok(Some(user)) = db.get_user_by_email(&email) {
+                if db.has_token(&user.id)? {
+                    return Ok(ResponsePayload::error(ErrorCode::EmailAlreadyRegistered));
+                }
             }
-        }
 
-        let veri_db = DatabaseState::init_with_table_name(
-            req.app_data::<AppState>().unwrap().db_path.clone(),
-            "verification".to_string(),
-        )
-        .expect("Failed to connect to Database!");
-        if let Ok(obj) = veri_db.generate_verification_code(email.to_string(), true) {
-            if let Some(code) = obj {
-                println!("{}", code);
-                // TODO: Send code via email
-                return HttpResponse::Ok().json(ResponsePayload::new_static_message(
-                    200,
-                    "Awaiting verification",
-                ));
+            let veri_db = DatabaseState::from_pool(&state.pool, "verification")?;
+            if let Ok(obj) = veri_db.generate_verification_code(email, true) {
+                if let Some(code) = obj {
+                    if let Err(err) =
+                        state_functions::send_verification_code(&state.mailer, &state.cfg, &code)
+                    {
+                        log::error!("Failed to send verification code!\n: {}", err);
+                        // Remove the entry again, so the email can be submitted once more
+                        if let Err(err) = veri_db.delete_verification_by_email(&code.email) {
+                            log::error!("{}", err);
+                        }
+                        return Ok(ResponsePayload::status_500());
+                    }
+                    Ok(ResponsePayload::new_static_message(
+                        200,
+                        "Awaiting verification",
+                    ))
+                } else {
+                    Ok(ResponsePayload::error(ErrorCode::EmailAlreadySubmitted))
+                }
             } else {
-                return HttpResponse::Conflict().json(ResponsePayload::new_static_message(
-                    409,
-                    "Email already submitted",
-                ));
+                Ok(ResponsePayload::status_500())
             }
-        } else {
-            return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
-        }
+        })
+        .await
     }
     // Serve Verification Endpoint
     #[get("/api/verify/{email}/{code}")]
     async fn verify(req: HttpRequest) -> HttpResponse {
-        let email: String = req.match_info().get("email").unwrap().parse().unwrap();
-        let code: u64 = req.match_info().get("code").unwrap().parse().unwrap();
+        let email = req.match_info().get("email").unwrap_or("").to_string();
+        let code: u64 = match req.match_info().get("code").unwrap_or("").parse() {
+            Ok(code) => code,
+            Err(err) => return ServerError::from(err).error_response(),
+        };
+        let state = app_state(&req);
 
-        let veri_db = DatabaseState::init_with_table_name(
-            req.app_data::<AppState>().unwrap().db_path.clone(),
-            "verification".to_string(),
-        )
-        .expect("Failed to connect to Database!");
+        blocking(move || {
+            let veri_db = DatabaseState::from_pool(&state.pool, "verification")?;
 
-        if let Ok(Some(verify_obj)) = veri_db.get_verification_by_email(&email) {
-            if verify_obj.code == code {
-                ()
+            if let Ok(Some(verify_obj)) = veri_db.get_verification_by_email(&email) {
+                if verify_obj.code != code {
+                    if veri_db
+                        .count_failed_attempt(&email, state.cfg.rate_limit_config.max_attempts)?
+                    {
+                        return Ok(ResponsePayload::error(ErrorCode::TooManyAttempts));
+                    }
+                    return Ok(ResponsePayload::error(ErrorCode::InvalidCode));
+                }
             } else {
-                return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                    401,
-                    "Email and/or Code Invalid",
-                ));
+                return Ok(ResponsePayload::error(ErrorCode::InvalidCode));
             }
-        } else {
-            return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                401,
-                "Email and/or Code Invalid",
-            ));
-        }
-        // It is now verfied that email and code are corresponding
-        // Next it is verified whether or not the code is valid.
-        // If it it's removed from the verification db and a new user is generated
-        if let Ok(Some(found_email)) = veri_db.verify_verification_code(code) {
-            let db = DatabaseState::init(req.app_data::<AppState>().unwrap().db_path.clone())
-                .expect("Failed to connect to Database!");
-            if let Ok(user) = db.new_user(&found_email) {
-                // Idk why I use found_email over email here. However it shouldn't make any difference
-                return HttpResponse::Ok()
-                    .json(ResponsePayload::new(200, ResponsePayloadTypes::User(user)));
+            // It is now verfied that email and code are corresponding
+            // Next it is verified whether or not the code is valid.
+            // If it it's removed from the verification db and a new user is generated
+            if let Ok(Some(found_email)) = veri_db.verify_verification_code(code) {
+                let db = DatabaseState::from_pool(&state.pool, "users")?;
+                // A user whose token was revoked gets a new one instead of a new account
+                if let Some(user) = db.get_user_by_email(&found_email)? {
+                    if db.has_token(&user.id)? {
+                        return Ok(ResponsePayload::error(ErrorCode::EmailAlreadyRegistered));
+                    }
+                    let token = DatabaseState::generate_token();
+                    db.set_user_token(&user.id, &token)?;
+                    if let Err(err) = state_functions::custom_log_line(
+                        &user,
+                        "A new token was issued".to_string(),
+                        &db,
+                    ) {
+                        log::error!("{}", err);
+                    }
+                    return Ok(ResponsePayload::new(
+                        200,
+                        ResponsePayloadTypes::Credentials(Credentials { user, token }),
+                    ));
+                }
+                if let Ok((user, token)) = db.new_user(&found_email) {
+                    // Idk why I use found_email over email here. However it shouldn't make any difference
+                    Ok(ResponsePayload::new(
+                        200,
+                        ResponsePayloadTypes::Credentials(Credentials { user, token }),
+                    ))
+                } else {
+                    Ok(ResponsePayload::status_500())
+                }
             } else {
-                return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+                Ok(ResponsePayload::error(ErrorCode::InvalidCode))
+            }
+        })
+        .await
+    }
+    // Serve Revival API
+    #[post("/api/revive")]
+    async fn request_revival(req: HttpRequest) -> HttpResponse {
+        let email = match match req.headers().get("Email") {
+            Some(val) => val.to_str().ok(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingEmail)),
+        } {
+            Some(email) => email.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingEmail)),
+        };
+        let state = app_state(&req);
+        blocking(move || {
+            // Only deceased users can be revived
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            match db.get_user_by_email(&email) {
+                Ok(Some(user)) if user.state.is_deceased() => (),
+                Ok(Some(_)) => return Ok(ResponsePayload::error(ErrorCode::NotDeceased)),
+                Ok(None) => return Ok(ResponsePayload::error(ErrorCode::EmailNotRegistered)),
+                Err(err) => return Err(err.into()),
+            }
+
+            let veri_db = DatabaseState::from_pool(&state.pool, "revival_verification")?;
+            match veri_db.generate_verification_code(email, true) {
+                Ok(Some(code)) => {
+                    if let Err(err) =
+                        state_functions::send_revival_code(&state.mailer, &state.cfg, &code)
+                    {
+                        log::error!("Failed to send revival code!\n: {}", err);
+                        // Remove the entry again, so the email can be submitted once more
+                        if let Err(err) = veri_db.delete_verification_by_email(&code.email) {
+                            log::error!("{}", err);
+                        }
+                        return Ok(ResponsePayload::status_500());
+                    }
+                    Ok(ResponsePayload::new_static_message(
+                        200,
+                        "Awaiting verification",
+                    ))
+                }
+                Ok(None) => Ok(ResponsePayload::error(ErrorCode::EmailAlreadySubmitted)),
+                Err(err) => Err(err.into()),
+            }
+        })
+        .await
+    }
+    // Serve Revival Verification Endpoint
+    #[get("/api/revive/{email}/{code}")]
+    async fn revive(req: HttpRequest) -> HttpResponse {
+        let email = req.match_info().get("email").unwrap_or("").to_string();
+        let code: u64 = match req.match_info().get("code").and_then(|v| v.parse().ok()) {
+            Some(code) => code,
+            None => return respond(ResponsePayload::error(ErrorCode::InvalidCode)),
+        };
+        let state = app_state(&req);
+
+        blocking(move || {
+            let veri_db = DatabaseState::from_pool(&state.pool, "revival_verification")?;
+            match veri_db.get_verification_by_email(&email) {
+                Ok(Some(verify_obj)) if verify_obj.code == code => (),
+                Ok(Some(_)) => {
+                    if veri_db
+                        .count_failed_attempt(&email, state.cfg.rate_limit_config.max_attempts)?
+                    {
+                        return Ok(ResponsePayload::error(ErrorCode::TooManyAttempts));
+                    }
+                    return Ok(ResponsePayload::error(ErrorCode::InvalidCode));
+                }
+                _ => return Ok(ResponsePayload::error(ErrorCode::InvalidCode)),
+            }
+            // Checks the expiration and removes the code
+            match veri_db.verify_verification_code(code) {
+                Ok(Some(_)) => (),
+                _ => return Ok(ResponsePayload::error(ErrorCode::InvalidCode)),
+            }
+
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match db.get_user_by_email(&email) {
+                Ok(Some(user)) => user,
+                Ok(None) => return Ok(ResponsePayload::error(ErrorCode::EmailNotRegistered)),
+                Err(err) => return Err(err.into()),
+            };
+            Ok(state_functions::revive(
+                user,
+                db,
+                &state.pool,
+                &state.mailer,
+                &state.cfg,
+                &state.key,
+            ))
+        })
+        .await
+    }
+    // Serve Email Change Verification Endpoint
+    #[get("/api/email/{email}/{code}")]
+    async fn confirm_email(req: HttpRequest) -> HttpResponse {
+        let email = req.match_info().get("email").unwrap_or("").to_string();
+        let code: u64 = match req.match_info().get("code").and_then(|v| v.parse().ok()) {
+            Some(code) => code,
+            None => return respond(ResponsePayload::error(ErrorCode::InvalidCode)),
+        };
+        let state = app_state(&req);
+
+        blocking(move || {
+            let veri_db = DatabaseState::from_pool(&state.pool, "email_verification")?;
+            match veri_db.get_verification_by_email(&email) {
+                Ok(Some(verify_obj)) if verify_obj.code == code => (),
+                Ok(Some(_)) => {
+                    if veri_db
+                        .count_failed_attempt(&email, state.cfg.rate_limit_config.max_attempts)?
+                    {
+                        return Ok(ResponsePayload::error(ErrorCode::TooManyAttempts));
+                    }
+                    return Ok(ResponsePayload::error(ErrorCode::InvalidCode));
+                }
+                _ => return Ok(ResponsePayload::error(ErrorCode::InvalidCode)),
+            }
+            // The user has to be looked up before the code is removed
+            let user_id = veri_db.get_verification_user(code)?;
+            // Checks the expiration and removes the code
+            match veri_db.verify_verification_code(code) {
+                Ok(Some(_)) => (),
+                _ => return Ok(ResponsePayload::error(ErrorCode::InvalidCode)),
+            }
+
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match user_id.map(|id| db.get_user_by_id(&id)).transpose()? {
+                Some(Some(user)) => user,
+                // The account was deleted in the meantime
+                _ => return Ok(ResponsePayload::error(ErrorCode::InvalidCode)),
+            };
+            if user.state.is_deceased() {
+                return Ok(ResponsePayload::error(ErrorCode::Deceased));
+            }
+            Ok(state_functions::confirm_email(user, db, email))
+        })
+        .await
+    }
+    /// Returns the alarm with the given acknowledgement code if the link may acknowledge it,
+    /// which is only the case once the contacts were notified. Until then only the user
+    /// checking in stops the alarm, the link only spares the admin.
+    fn acknowledgeable_alarm(
+        alarm_db: &DatabaseState,
+        code: &str,
+    ) -> Result<Result<Alarm, ErrorCode>, rusqlite::Error> {
+        Ok(match alarm_db.get_alarm_by_ack_code(code)? {
+            Some(alarm) if alarm.contacts_notified.is_some() => Ok(alarm),
+            Some(_) => Err(ErrorCode::AlarmNotEscalated),
+            None => Err(ErrorCode::AlarmNotFound),
+        })
+    }
+    // Serve Alarm Acknowledgement Page
+    // The link in the emails leads here, opening it (e.g. by a link scanner) changes nothing
+    #[get("/api/alarm/acknowledge/{code}")]
+    async fn acknowledge_page(req: HttpRequest) -> HttpResponse {
+        let code = req.match_info().get("code").unwrap_or("").to_string();
+        let state = app_state(&req);
+
+        let page = web::block(move || -> Result<Result<String, ErrorCode>, ServerError> {
+            let alarm_db = DatabaseState::from_pool(&state.pool, "alarms")?;
+            let alarm = match acknowledgeable_alarm(&alarm_db, &code)? {
+                Ok(alarm) => alarm,
+                Err(code) => return Ok(Err(code)),
+            };
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let mut values = HashMap::new();
+            values.insert(
+                "email",
+                db.get_user_by_id(&alarm.id)?
+                    .map(|user| user.email)
+                    .unwrap_or_default(),
+            );
+            values.insert("raised", smtp_handler::format_timestamp(alarm.raised));
+            values.insert(
+                "contacts_notified",
+                smtp_handler::format_timestamp(alarm.contacts_notified.unwrap_or_default()),
+            );
+            values.insert(
+                "ack_link",
+                format!(
+                    "{}/api/alarm/acknowledge/{}",
+                    state.cfg.server_config.public_url.trim_end_matches('/'),
+                    alarm.ack_code
+                ),
+            );
+            Ok(Ok(smtp_handler::render_template_file(
+                &state.cfg.file_locations.acknowledge_page_scheme,
+                &values,
+            )?))
+        })
+        .await;
+        match page {
+            Ok(Ok(page)) => HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(page),
+            Ok(Err(code)) => respond(ResponsePayload::error(code)),
+            Err(err) => {
+                let err = ServerError::from(err);
+                log::error!("{}", err);
+                err.error_response()
             }
-        } else {
-            return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                401,
-                "Email and/or Code Invalid",
-            ));
         }
     }
+    // Serve Alarm Acknowledgement Endpoint
+    #[post("/api/alarm/acknowledge/{code}")]
+    async fn acknowledge(req: HttpRequest) -> HttpResponse {
+        let code = req.match_info().get("code").unwrap_or("").to_string();
+        let state = app_state(&req);
+
+        blocking(move || {
+            let alarm_db = DatabaseState::from_pool(&state.pool, "alarms")?;
+            let mut alarm = match acknowledgeable_alarm(&alarm_db, &code)? {
+                Ok(alarm) => alarm,
+                Err(code) => return Ok(ResponsePayload::error(code)),
+            };
+            if alarm.acknowledged.is_some() {
+                return Ok(ResponsePayload::new_static_message(
+                    200,
+                    "Alarm already acknowledged",
+                ));
+            }
+
+            alarm.acknowledged = Some(
+                chrono::offset::Utc::now()
+                    .timestamp()
+                    .try_into()
+                    .expect("Time went backwards"),
+            );
+            alarm_db.set_alarm(&alarm)?;
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            if let Ok(Some(user)) = db.get_user_by_id(&alarm.id) {
+                if let Err(err) = state_functions::custom_log_line(
+                    &user,
+                    "The alarm was acknowledged".to_string(),
+                    &db,
+                ) {
+                    log::error!("{}", err);
+                }
+            }
+            Ok(ResponsePayload::new_static_message(
+                200,
+                "Alarm acknowledged",
+            ))
+        })
+        .await
+    }
     // Serve User-Settings API
     #[post("/api/settings")]
     async fn settings(req: HttpRequest, info: web::Json<RequestPayload>) -> HttpResponse {
-        let user_id = match match req.headers().get("User-Token") {
+        let token = match match req.headers().get("User-Token") {
             Some(auth) => auth.to_str().ok(),
-            None => {
-                return HttpResponse::Unauthorized().json(ResponsePayload::new_static_message(
-                    401,
-                    "No User Token Provided",
-                ))
-            }
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
         } {
-            Some(auth) => auth,
-            None => {
-                return HttpResponse::Unauthorized().json(ResponsePayload::new_static_message(
-                    401,
-                    "No User Token Provided",
-                ))
+            Some(auth) => auth.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        };
+
+        let mtype = match req.headers().get("Message-Type") {
+            Some(val) => val.to_str().unwrap_or("A"),
+            None => "A",
+        }
+        .to_string();
+
+        let state = app_state(&req);
+        blocking(move || {
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match db.get_user_by_token(&token)? {
+                Some(u) => u,
+                None => return Ok(ResponsePayload::error(ErrorCode::InvalidUserToken)),
+            };
+            // Deceased users may only look at their settings or delete their account
+            if user.state.is_deceased() && !matches!(mtype.as_str(), "A" | "F") {
+                return Ok(ResponsePayload::error(ErrorCode::Deceased));
+            }
+            let settings_db = DatabaseState::from_pool(&state.pool, "settings")?;
+
+            match mtype.as_str() {
+                "A" => match settings_db.get_settings_by_id(&user.id) {
+                    Ok(settings) => Ok(ResponsePayload::new(
+                        200,
+                        ResponsePayloadTypes::Settings(
+                            settings.unwrap_or_else(|| UserSettings::default_for(&user.id)),
+                        ),
+                    )),
+                    Err(err) => Err(err.into()),
+                },
+                "B" => Ok(state_functions::change_interval(user, settings_db, info)),
+                "C" => Ok(state_functions::change_email(
+                    user,
+                    db,
+                    info,
+                    &state.pool,
+                    &state.mailer,
+                    &state.cfg,
+                )),
+                "D" => Ok(state_functions::rotate_token(
+                    user,
+                    db,
+                    state.cfg.server_config.token_overlap,
+                )),
+                "E" => Ok(state_functions::pause(user, db, info)),
+                "G" => Ok(state_functions::change_policy(
+                    user,
+                    settings_db,
+                    DatabaseState::from_pool(&state.pool, "deadlines")?,
+                    state.tx.clone(),
+                    info,
+                )),
+                "H" => Ok(state_functions::forget_beacon(
+                    user,
+                    DatabaseState::from_pool(&state.pool, "deadlines")?,
+                    state.tx.clone(),
+                    info,
+                )),
+                "I" => Ok(state_functions::change_retention(
+                    user,
+                    settings_db,
+                    info,
+                    &state.cfg.audit_config,
+                )),
+                "F" => Ok(state_functions::delete_account(user, db, info)),
+                _ => Ok(ResponsePayload::error(ErrorCode::InvalidMessageType)),
             }
+        })
+        .await
+    }
+    // Serve Token API
+    #[post("/api/token")]
+    async fn manage_token(req: HttpRequest) -> HttpResponse {
+        let token = match match req.headers().get("Auth-Token") {
+            Some(auth) => auth.to_str().ok(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingAuthToken)),
+        } {
+            Some(auth) => auth.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingAuthToken)),
         };
 
         let mtype = match req.headers().get("Message-Type") {
             Some(val) => val.to_str().unwrap_or("A"),
             None => "A",
+        }
+        .to_string();
+
+        let state = app_state(&req);
+        blocking(move || {
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match db.get_user_by_token(&token)? {
+                Some(u) => u,
+                None => return Ok(ResponsePayload::error(ErrorCode::InvalidAuthToken)),
+            };
+            // A leaked token has to be replaceable in every state, so deceased users may use this too
+
+            match mtype.as_str() {
+                "A" => Ok(state_functions::rotate_token(
+                    user,
+                    db,
+                    state.cfg.server_config.token_overlap,
+                )),
+                "B" => Ok(state_functions::revoke_token(user, db)),
+                _ => Ok(ResponsePayload::error(ErrorCode::InvalidMessageType)),
+            }
+        })
+        .await
+    }
+    // Serve Devices API
+    #[post("/api/devices")]
+    async fn devices(req: HttpRequest, info: web::Json<RequestPayload>) -> HttpResponse {
+        let token = match match req.headers().get("User-Token") {
+            Some(auth) => auth.to_str().ok(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        } {
+            Some(auth) => auth.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
         };
 
-        let db = DatabaseState::init_with_table_name(
-            req.app_data::<AppState>().unwrap().db_path.clone(),
-            "verification".to_string(),
-        )
-        .expect("Failed to connect to database!");
-
-        match mtype {
-            "A" => return HttpResponse::Ok().body("200 - Nothing Happened"),
-            "B" => return HttpResponse::Ok().body("200 - Dummy..."),
-            "C" => return HttpResponse::Ok().body("200 - Dummy..."),
-            "D" => return HttpResponse::Ok().body("200 - Dummy..."),
-            "E" => return HttpResponse::Ok().body("200 - Dummy..."),
-            "F" => return HttpResponse::Ok().body("200 - Dummy..."),
-            _ => {
-                return HttpResponse::NotFound().json(ResponsePayload::new_static_message(
-                    404,
-                    "Message Type Invalid",
-                ))
+        let mtype = match req.headers().get("Message-Type") {
+            Some(val) => val.to_str().unwrap_or("A"),
+            None => "A",
+        }
+        .to_string();
+
+        let state = app_state(&req);
+        blocking(move || {
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match db.get_user_by_token(&token)? {
+                Some(u) => u,
+                None => return Ok(ResponsePayload::error(ErrorCode::InvalidUserToken)),
+            };
+            // Deceased users don't send heartbeats anymore, but may still revoke a lost device
+            if user.state.is_deceased() && mtype == "B" {
+                return Ok(ResponsePayload::error(ErrorCode::Deceased));
             }
+            let device_db = DatabaseState::from_pool(&state.pool, "devices")?;
+
+            match mtype.as_str() {
+                "A" => match device_db.get_devices_by_user(&user.id) {
+                    Ok(devices) => Ok(ResponsePayload::new(
+                        200,
+                        ResponsePayloadTypes::Devices(devices),
+                    )),
+                    Err(err) => Err(err.into()),
+                },
+                "B" => Ok(state_functions::add_device(user, device_db, info)),
+                "C" => Ok(state_functions::remove_device(user, device_db, info)),
+                _ => Ok(ResponsePayload::error(ErrorCode::InvalidMessageType)),
+            }
+        })
+        .await
+    }
+    // Serve Contacts API
+    #[post("/api/contacts")]
+    async fn contacts(req: HttpRequest, info: web::Json<RequestPayload>) -> HttpResponse {
+        let token = match match req.headers().get("User-Token") {
+            Some(auth) => auth.to_str().ok(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        } {
+            Some(auth) => auth.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        };
+
+        let mtype = match req.headers().get("Message-Type") {
+            Some(val) => val.to_str().unwrap_or("A"),
+            None => "A",
         }
+        .to_string();
+
+        let state = app_state(&req);
+        blocking(move || {
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match db.get_user_by_token(&token)? {
+                Some(u) => u,
+                None => return Ok(ResponsePayload::error(ErrorCode::InvalidUserToken)),
+            };
+            // Contacts of deceased users are already notified, changing them has no effect anymore
+            if user.state.is_deceased() && mtype != "A" {
+                return Ok(ResponsePayload::error(ErrorCode::Deceased));
+            }
+            let contact_db = DatabaseState::from_pool(&state.pool, "contacts")?;
+
+            match mtype.as_str() {
+                "A" => match contact_db.get_contacts_by_user(&user.id) {
+                    Ok(contacts) => Ok(ResponsePayload::new(
+                        200,
+                        ResponsePayloadTypes::Contacts(contacts),
+                    )),
+                    Err(err) => Err(err.into()),
+                },
+                "B" => Ok(state_functions::add_contact(
+                    user,
+                    contact_db,
+                    info,
+                    &state.pool,
+                    &state.mailer,
+                    &state.cfg,
+                    &state.key,
+                )),
+                "C" => Ok(state_functions::remove_contact(user, contact_db, info)),
+                _ => Ok(ResponsePayload::error(ErrorCode::InvalidMessageType)),
+            }
+        })
+        .await
+    }
+    // Serve Contact Confirmation Endpoint
+    #[get("/api/contacts/confirm/{id}/{code}")]
+    async fn confirm_contact(req: HttpRequest) -> HttpResponse {
+        let (id, code): (u32, u64) = match (
+            req.match_info().get("id").and_then(|v| v.parse().ok()),
+            req.match_info().get("code").and_then(|v| v.parse().ok()),
+        ) {
+            (Some(id), Some(code)) => (id, code),
+            _ => return respond(ResponsePayload::error(ErrorCode::InvalidContactCode)),
+        };
+        let state = app_state(&req);
+
+        blocking(move || {
+            let contact_db = DatabaseState::from_pool(&state.pool, "contacts")?;
+            let contact = match contact_db.get_contact_by_id(id) {
+                Ok(Some(contact)) => contact,
+                Ok(None) => return Ok(ResponsePayload::error(ErrorCode::ContactNotFound)),
+                Err(err) => return Err(err.into()),
+            };
+            if contact.active {
+                return Ok(ResponsePayload::new_static_message(
+                    200,
+                    "Contact already confirmed",
+                ));
+            }
+            let veri_db = DatabaseState::from_pool(&state.pool, "contact_verification")?;
+            // The code has to belong to the address of the contact,
+            // every other code counts as a failed attempt to confirm it
+            match veri_db.verify_verification_code(code) {
+                Ok(Some(email)) if email == contact.email => (),
+                Ok(_) => {
+                    if veri_db.count_failed_attempt(
+                        &contact.email,
+                        state.cfg.rate_limit_config.max_attempts,
+                    )? {
+                        return Ok(ResponsePayload::error(ErrorCode::TooManyAttempts));
+                    }
+                    return Ok(ResponsePayload::error(ErrorCode::InvalidContactCode));
+                }
+                Err(err) => return Err(err.into()),
+            }
+            contact_db.activate_contact(contact.id)?;
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            if let Ok(Some(user)) = db.get_user_by_id(&contact.user_id) {
+                if let Err(err) = state_functions::custom_log_line(
+                    &user,
+                    format!("Contact {} confirmed", contact.email),
+                    &db,
+                ) {
+                    log::error!("{}", err);
+                }
+            }
+            Ok(ResponsePayload::new_static_message(
+                200,
+                "Contact confirmed",
+            ))
+        })
+        .await
+    }
+    // Serve Contact Unsubscribe Endpoint
+    #[get("/api/contacts/unsubscribe/{id}/{signature}")]
+    async fn unsubscribe_contact(req: HttpRequest) -> HttpResponse {
+        let id: u32 = match req.match_info().get("id").and_then(|v| v.parse().ok()) {
+            Some(id) => id,
+            None => return respond(ResponsePayload::error(ErrorCode::InvalidLink)),
+        };
+        let signature = req.match_info().get("signature").unwrap_or("").to_string();
+        let state = app_state(&req);
+
+        blocking(move || {
+            let contact_db = DatabaseState::from_pool(&state.pool, "contacts")?;
+            let contact = match contact_db.get_contact_by_id(id) {
+                Ok(Some(contact))
+                    if state_functions::verify_unsubscribe(&state.key, &contact, &signature) =>
+                {
+                    contact
+                }
+                // Already removed contacts are treated just like invalid links
+                Ok(_) => return Ok(ResponsePayload::error(ErrorCode::InvalidLink)),
+                Err(err) => return Err(err.into()),
+            };
+            contact_db.delete_contact(&contact.user_id, contact.id)?;
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            if let Ok(Some(user)) = db.get_user_by_id(&contact.user_id) {
+                if let Err(err) = state_functions::custom_log_line(
+                    &user,
+                    format!("Contact {} unsubscribed", contact.email),
+                    &db,
+                ) {
+                    log::error!("{}", err);
+                }
+            }
+            Ok(ResponsePayload::new_static_message(200, "Unsubscribed"))
+        })
+        .await
+    }
+    // Serve Audit Log API
+    #[get("/api/audit")]
+    async fn audit_entries(req: HttpRequest, query: web::Query<AuditQuery>) -> HttpResponse {
+        let token = match match req.headers().get("User-Token") {
+            Some(auth) => auth.to_str().ok(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        } {
+            Some(auth) => auth.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        };
+        let query = query.into_inner();
 
-        // TODO: Work here
+        let state = app_state(&req);
+        blocking(move || {
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match db.get_user_by_token(&token)? {
+                Some(u) => u,
+                None => return Ok(ResponsePayload::error(ErrorCode::InvalidUserToken)),
+            };
+            Ok(state_functions::audit_entries(
+                user,
+                &query,
+                DatabaseState::from_pool(&state.pool, "audit")?,
+            ))
+        })
+        .await
+    }
+    // Serve Audit Log Verification API
+    #[get("/api/audit/verify")]
+    async fn verify_audit(req: HttpRequest) -> HttpResponse {
+        let token = match match req.headers().get("User-Token") {
+            Some(auth) => auth.to_str().ok(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        } {
+            Some(auth) => auth.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingUserToken)),
+        };
+
+        let state = app_state(&req);
+        blocking(move || {
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            let user = match db.get_user_by_token(&token)? {
+                Some(u) => u,
+                None => return Ok(ResponsePayload::error(ErrorCode::InvalidUserToken)),
+            };
+            Ok(state_functions::verify_audit_log(
+                user,
+                &state.key,
+                DatabaseState::from_pool(&state.pool, "audit")?,
+            ))
+        })
+        .await
+    }
+    // Serve Error Catalogue Endpoint
+    #[get("/api/errors")]
+    async fn errors() -> HttpResponse {
+        respond(ResponsePayload::new(
+            200,
+            ResponsePayloadTypes::Errors(ErrorCode::ALL.iter().map(|&code| code.into()).collect()),
+        ))
+    }
+    /// Answers requests to unknown endpoints
+    async fn not_found() -> HttpResponse {
+        respond(ResponsePayload::error(ErrorCode::NotFound))
     }
     // Serve Account State API
     #[post("/api/infos")]
     async fn callback(req: HttpRequest, info: web::Json<RequestPayload>) -> HttpResponse {
         // Parse the Auth header from the request and return 401 if the header is not present or not readable.
-        let auth_id = match match req.headers().get("Auth-Token") {
+        let token = match match req.headers().get("Auth-Token") {
             Some(auth) => auth.to_str().ok(),
-            None => {
-                return HttpResponse::Unauthorized().json(ResponsePayload::new_static_message(
-                    401,
-                    "No Auth Token Provided",
-                ))
-            }
+            None => return respond(ResponsePayload::error(ErrorCode::MissingAuthToken)),
         } {
-            Some(auth) => auth,
-            None => {
-                return HttpResponse::Unauthorized().json(ResponsePayload::new_static_message(
-                    401,
-                    "No Auth Token Provided",
-                ))
-            }
+            Some(auth) => auth.to_string(),
+            None => return respond(ResponsePayload::error(ErrorCode::MissingAuthToken)),
         };
 
         let mtype = match req.headers().get("Message-Type") {
             Some(val) => val.to_str().unwrap_or("0"),
             None => "0",
-        };
+        }
+        .to_string();
 
-        let db = DatabaseState::init(req.app_data::<AppState>().unwrap().db_path.clone())
-            .expect("Failed to connect to Database!");
-        let user = match db.get_user_by_id(&auth_id.to_string()) {
-            Ok(val) => match val {
-                Some(u) => u,
-                None => {
-                    return HttpResponse::Unauthorized().json(ResponsePayload::new_static_message(
-                        401,
-                        "Auth Token Invalid",
-                    ))
-                }
-            },
-            Err(_) => {
-                return HttpResponse::InternalServerError().json(ResponsePayload::status_500())
-            }
-        };
+        let state = app_state(&req);
+        blocking(move || {
+            let db = DatabaseState::from_pool(&state.pool, "users")?;
+            // Either the token of the user itself or the token of one of its devices
+            let (user, device) = match authenticate(&state.pool, &db, &token) {
+                Ok(Some(val)) => val,
+                Ok(None) => return Ok(ResponsePayload::error(ErrorCode::InvalidAuthToken)),
+                Err(err) => return Err(err.into()),
+            };
+
+            // The default check-in interval of the user, used if a heartbeat contains no `Td`
+            let default_interval = DatabaseState::from_pool(&state.pool, "settings")
+                .and_then(|settings_db| settings_db.get_settings_by_id(&user.id))
+                .ok()
+                .flatten()
+                .and_then(|user_settings| user_settings.interval);
 
-        match mtype {
-            "0" => return state_functions::test(),
-            "1" => {
-                return state_functions::audit(
+            match mtype.as_str() {
+                "0" => Ok(state_functions::test()),
+                "1" => Ok(state_functions::audit(
                     user,
-                    req.app_data::<AppState>().unwrap().tx.clone(),
+                    device.as_ref(),
+                    DatabaseState::from_pool(&state.pool, "deadlines")?,
+                    state.tx.clone(),
                     info,
-                    &req.app_data::<AppState>()
-                        .unwrap()
-                        .cfg
-                        .file_locations
-                        .log_folder,
-                )
-            }
-            "2" => {
-                return state_functions::sign(
+                    default_interval,
+                )),
+                "2" => Ok(state_functions::sign(
                     user,
+                    device.as_ref(),
                     db,
+                    DatabaseState::from_pool(&state.pool, "alarms")?,
                     info,
-                    &req.app_data::<AppState>()
-                        .unwrap()
-                        .cfg
-                        .file_locations
-                        .log_folder,
-                )
-            }
-            "3" => {
-                return state_functions::ilive(
+                )),
+                "3" => Ok(state_functions::ilive(
                     user,
+                    device.as_ref(),
                     db,
-                    req.app_data::<AppState>().unwrap().tx.clone(),
+                    DatabaseState::from_pool(&state.pool, "deadlines")?,
+                    state.tx.clone(),
                     info,
-                    &req.app_data::<AppState>()
-                        .unwrap()
-                        .cfg
-                        .file_locations
-                        .log_folder,
-                )
-            }
-            "4" => {
-                return state_functions::stat(user, req.app_data::<AppState>().unwrap().init_time)
-            }
-            _ => {
-                return HttpResponse::NotFound().json(ResponsePayload::new_static_message(
-                    404,
-                    "Message Type Invalid",
-                ))
+                    default_interval,
+                )),
+                "4" => Ok(state_functions::stat(user, state.init_time)),
+                "5" => Ok(state_functions::history(user, &state.pool)),
+                _ => Ok(ResponsePayload::error(ErrorCode::InvalidMessageType)),
             }
-        }
+        })
+        .await
     }
 
     #[derive(Debug, Clone)]
     pub struct AppState {
-        db_path: String,
+        pool: DbPool,
         cfg: ConfigMain,
         tx: Sender<(String, u32)>,
+        mailer: Mailer,
+        key: ServerKey,
         init_time: u32,
     }
     #[actix_web::main]
     pub async fn run(
         config: ConfigMain,
         time_state_transmitter: Sender<(String, u32)>,
+        mailer: Mailer,
+        key: ServerKey,
+        pool: DbPool,
     ) -> std::io::Result<()> {
+        // The counters of the rate limiter are shared by all workers
+        let rate_limit = RateLimit::new(config.rate_limit_config.clone());
         // Init Database
-        let state = AppState {
-            db_path: config.file_locations.database_path.clone(),
+        let state = web::Data::new(AppState {
+            pool,
             cfg: config,
             tx: time_state_transmitter,
+            mailer,
+            key,
             init_time: chrono::offset::Utc::now()
                 .timestamp()
                 .try_into()
                 .expect("Time went backwards"),
-        };
+        });
         // u8 Array for private cokkie session
         let mut rng = rand::thread_rng();
         let mut arr = [0; 32];
@@ -291,10 +915,34 @@ pub mod handler {
         HttpServer::new(move || {
             App::new()
                 .app_data(state.clone())
+                // Malformed payloads are answered like every other error
+                .app_data(
+                    web::JsonConfig::default()
+                        .error_handler(|err, _req| ServerError::from(err).into()),
+                )
+                .app_data(
+                    web::QueryConfig::default()
+                        .error_handler(|err, _req| ServerError::from(err).into()),
+                )
                 .service(register)
                 .service(verify)
+                .service(confirm_email)
+                .service(acknowledge_page)
+                .service(acknowledge)
+                .service(request_revival)
+                .service(revive)
                 .service(settings)
+                .service(manage_token)
+                .service(devices)
+                .service(contacts)
+                .service(confirm_contact)
+                .service(unsubscribe_contact)
                 .service(callback)
+                .service(audit_entries)
+                .service(verify_audit)
+                .service(errors)
+                .default_service(web::route().to(not_found))
+                .wrap(rate_limit.clone())
                 .wrap(Logger::new("%{r}a - [%tUTC] %r | %s %b "))
         })
         .workers(6)
@@ -0,0 +1,110 @@
+pub mod deadline_scheduler {
+    use std::{
+        cmp::Reverse,
+        collections::{BinaryHeap, HashMap},
+    };
+
+    /// Keeps the deadlines of all watched users ordered by their expiry.
+    /// A replaced deadline stays in the heap and is skipped once it comes up, so setting a
+    /// deadline stays cheap. The heap is rebuilt if it holds too many of those stale entries.
+    #[derive(Debug, Default)]
+    pub struct DeadlineScheduler {
+        heap: BinaryHeap<Reverse<(u32, String)>>,
+        deadlines: HashMap<String, u32>,
+    }
+    impl DeadlineScheduler {
+        /// Create a new scheduler watching the given deadlines (user id -> expiry)
+        pub fn from_deadlines(deadlines: HashMap<String, u32>) -> Self {
+            let heap = deadlines
+                .iter()
+                .map(|(id, expires)| Reverse((*expires, id.clone())))
+                .collect();
+            Self { heap, deadlines }
+        }
+        /// Set the deadline of the given user, replacing an earlier one
+        pub fn set(&mut self, id: String, expires: u32) {
+            self.deadlines.insert(id.clone(), expires);
+            self.heap.push(Reverse((expires, id)));
+            if self.heap.len() > 2 * self.deadlines.len() + 1024 {
+                self.heap = self
+                    .deadlines
+                    .iter()
+                    .map(|(id, expires)| Reverse((*expires, id.clone())))
+                    .collect();
+            }
+        }
+        /// The number of watched users
+        pub fn len(&self) -> usize {
+            self.deadlines.len()
+        }
+        /// Whether no user is watched
+        pub fn is_empty(&self) -> bool {
+            self.deadlines.is_empty()
+        }
+        /// Returns the earliest deadline, None if no user is watched
+        pub fn next_expiry(&mut self) -> Option<u32> {
+            self.drop_stale();
+            self.heap.peek().map(|Reverse((expires, _))| *expires)
+        }
+        /// Removes and returns the ids of all users whose deadline lies before the given time
+        pub fn pop_expired(&mut self, now: u32) -> Vec<String> {
+            let mut expired = vec![];
+            while let Some(expires) = self.next_expiry() {
+                if expires >= now {
+                    break;
+                }
+                if let Some(Reverse((_, id))) = self.heap.pop() {
+                    self.deadlines.remove(&id);
+                    expired.push(id);
+                }
+            }
+            expired
+        }
+        /// Pops heap entries whose deadline was replaced or already expired
+        fn drop_stale(&mut self) {
+            while let Some(Reverse((expires, id))) = self.heap.peek() {
+                if self.deadlines.get(id) == Some(expires) {
+                    break;
+                }
+                self.heap.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheduler::deadline_scheduler::DeadlineScheduler;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn expire_in_order() {
+        let mut restored = HashMap::new();
+        restored.insert("foo".to_string(), 30);
+        let mut scheduler = DeadlineScheduler::from_deadlines(restored);
+        scheduler.set("bar".to_string(), 10);
+        scheduler.set("baz".to_string(), 20);
+        // A heartbeat replaces the earlier deadline
+        scheduler.set("bar".to_string(), 40);
+        assert_eq!(scheduler.len(), 3);
+        assert_eq!(scheduler.next_expiry(), Some(20));
+
+        assert_eq!(scheduler.pop_expired(20), Vec::<String>::new());
+        assert_eq!(scheduler.pop_expired(35), vec!["baz", "foo"]);
+        assert_eq!(scheduler.next_expiry(), Some(40));
+        assert_eq!(scheduler.pop_expired(100), vec!["bar"]);
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.next_expiry(), None);
+    }
+    #[test]
+    fn compact_stale_entries() {
+        let mut scheduler = DeadlineScheduler::default();
+        for time in 0..5000 {
+            scheduler.set("foo".to_string(), time);
+        }
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(scheduler.next_expiry(), Some(4999));
+        assert_eq!(scheduler.pop_expired(5000), vec!["foo"]);
+    }
+}
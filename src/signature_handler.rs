@@ -0,0 +1,107 @@
+pub mod signing {
+    use hmac::{Hmac, Mac};
+    use rand::Rng;
+    use sha2::{Digest, Sha256};
+    use std::{convert::TryInto, fs, io, path::Path};
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    /// The secret key of this server, used to sign links and data handed out to the public
+    #[derive(Clone)]
+    pub struct ServerKey {
+        key: [u8; 32],
+    }
+    impl std::fmt::Debug for ServerKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            // Never print the key itself
+            write!(f, "ServerKey(..)")
+        }
+    }
+    impl ServerKey {
+        /// Reads the key from the given path, a new random key is generated and written
+        /// to the path if no key exists yet.
+        pub fn load_or_create(path: &str) -> Result<Self, io::Error> {
+            if Path::new(path).exists() {
+                let content = fs::read_to_string(path)?;
+                let bytes = hex::decode(content.trim())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let key: [u8; 32] = bytes.try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Server key has to be 32 bytes")
+                })?;
+                return Ok(Self { key });
+            }
+            log::info!("No server key found, generating a new one...");
+            let key: [u8; 32] = rand::thread_rng().gen();
+            fs::write(path, hex::encode(key))?;
+            Ok(Self { key })
+        }
+        /// Create a key from the given bytes
+        pub fn from_bytes(key: [u8; 32]) -> Self {
+            Self { key }
+        }
+        /// Returns the hex encoded HMAC-SHA256 signature of the given message
+        pub fn sign(&self, message: &str) -> String {
+            let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes any key size");
+            mac.update(message.as_bytes());
+            hex::encode(mac.finalize().into_bytes())
+        }
+        /// Verifies the hex encoded signature of the given message in constant time
+        pub fn verify(&self, message: &str, signature: &str) -> bool {
+            let signature = match hex::decode(signature) {
+                Ok(val) => val,
+                Err(_) => return false,
+            };
+            let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes any key size");
+            mac.update(message.as_bytes());
+            mac.verify_slice(&signature).is_ok()
+        }
+    }
+
+    /// Returns the hex encoded SHA256 hash of the given data
+    pub fn hash(data: &str) -> String {
+        hex::encode(Sha256::digest(data.as_bytes()))
+    }
+
+    /// Hashes an API token with the given salt for storage,
+    /// returns the hex encoded HMAC-SHA256 of the token keyed with the salt
+    pub fn hash_token(token: &str, salt: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(salt.as_bytes()).expect("HMAC takes any key size");
+        mac.update(token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+    /// Verifies the given token against a stored hash (see `hash_token`) in constant time
+    pub fn verify_token(token: &str, salt: &str, hash: &str) -> bool {
+        let hash = match hex::decode(hash) {
+            Ok(val) => val,
+            Err(_) => return false,
+        };
+        let mut mac = HmacSha256::new_from_slice(salt.as_bytes()).expect("HMAC takes any key size");
+        mac.update(token.as_bytes());
+        mac.verify_slice(&hash).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signature_handler::signing::{hash_token, verify_token, ServerKey};
+
+    #[test]
+    fn sign_and_verify() {
+        let key = ServerKey::from_bytes([7; 32]);
+        let other = ServerKey::from_bytes([8; 32]);
+        let signature = key.sign("foo");
+        assert!(key.verify("foo", &signature));
+        assert!(!key.verify("bar", &signature));
+        assert!(!other.verify("foo", &signature));
+        assert!(!key.verify("foo", "not hex"));
+    }
+    #[test]
+    fn hash_and_verify_token() {
+        let hash = hash_token("token", "salt");
+        assert_ne!(hash, hash_token("token", "other salt"));
+        assert!(verify_token("token", "salt", &hash));
+        assert!(!verify_token("other token", "salt", &hash));
+        assert!(!verify_token("token", "other salt", &hash));
+        assert!(!verify_token("token", "salt", "not hex"));
+    }
+}
@@ -1,72 +1,58 @@
 pub mod state_functions {
     #![allow(non_snake_case)]
 
+    use crate::audit::audit_log::{self, AuditKind, AuditRecord};
     use crate::data::data_forms::{
-        RequestPayload, ResponsePayload, ResponsePayloadTypes, ServerStatus, User,
+        AuditQuery, BeaconPolicy, ConfigAudit, ConfigMain, Contact, Credentials, Device,
+        DeviceCredentials, RequestPayload, ResponsePayload, ResponsePayloadTypes, ServerStatus,
+        StateChange, TransitionCause, User, UserSettings, UserState, Verification,
     };
-    use crate::data_handler::sqlite_handler::DatabaseState;
+    use crate::data_handler::sqlite_handler::{DatabaseState, DbPool};
+    use crate::error::server_error::ErrorCode;
+    use crate::mail_handler::smtp_handler::{self, Mailer};
+    use crate::signature_handler::signing::ServerKey;
 
-    use actix_web::{web, HttpResponse};
-    use chrono::{DateTime, Local, Utc};
-    use linecount::count_lines;
+    use actix_web::web;
+    use chrono::Utc;
     use std::{
         collections::HashMap,
         convert::{TryFrom, TryInto},
-        fs::OpenOptions,
-        io::{prelude::*, BufReader},
         sync::mpsc::Sender,
     };
 
-    impl RequestPayload {
-        fn log_audit(&self, user: &User, logpath: &str) -> Result<(), std::io::Error> {
-            let fullpath = format!("{}{}.log", logpath, user.clone().id);
-            let mut file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(&fullpath)
-                .unwrap();
-
-            let lines = BufReader::new(&file).lines();
-
-            let linec_diff =
-                (count_lines(std::fs::File::open(&fullpath)?)? as isize - 249) as usize;
-            let mut content = if linec_diff > 0 {
-                lines
-                    .skip(linec_diff)
-                    .map(|x| x.unwrap())
-                    .collect::<Vec<String>>()
-            } else {
-                lines.map(|x| x.unwrap()).collect::<Vec<String>>()
-            };
-
-            let utc_time = DateTime::<Utc>::from_utc(Local::now().naive_utc(), Utc);
-            let time_diff = utc_time.timestamp() - self.T.unwrap_or(0) as i64;
+    /// The maximum number of trusted contacts per user
+    const MAX_CONTACTS: usize = 10;
+    /// The beacon of heartbeats which name no beacon and weren't sent by a device
+    const DEFAULT_BEACON: &str = "default";
+    /// The maximum number of devices per user
+    const MAX_DEVICES: usize = 10;
+    /// Seconds a contact has to confirm it wants to receive notifications
+    const CONTACT_CONFIRMATION_LIFETIME: u32 = 7 * 24 * 3600;
 
-            // Log format:
-            // %u %d - %l; %o
-            // %u UTC Time at the time of the log entry
-            // %d Seconds since the client sent the request
-            // %l [Latitude, Longitude, other]
-            // %o {"key": ["values"], "key": ["values"], ...}
-            let new_line = format!(
-                "{} {} - {:?}; {:?}\n",
-                utc_time,
-                time_diff,
-                self.L.as_ref().unwrap_or(&vec!["-".to_string()]),
-                self.O.as_ref().unwrap_or(&HashMap::new())
-            );
-            content.push(new_line);
-
-            file.write(content.join("\n").as_bytes())?;
-            file.flush()?;
-            Ok(())
+    impl RequestPayload {
+        /// Appends this payload to the audit log of the user, a request made by a device
+        /// is attributed to it by its name and id
+        fn log_audit(
+            &self,
+            kind: AuditKind,
+            user: &User,
+            device: Option<&Device>,
+            db: &DatabaseState,
+        ) -> Result<(), rusqlite::Error> {
+            let time = Utc::now()
+                .timestamp()
+                .try_into()
+                .expect("Time went backwards");
+            db.append_audit(
+                &user.id,
+                AuditRecord::from_payload(kind, self, device, time),
+            )
         }
     }
 
     /// Returns True if the difference between the given Timestamp and now is greater than zero
     fn is_positive(timestamp: &Option<u32>) -> bool {
-        let utc_time = DateTime::<Utc>::from_utc(Local::now().naive_utc(), Utc);
+        let utc_time = Utc::now();
         let time_diff = utc_time.timestamp() - timestamp.unwrap_or(0) as i64;
         if time_diff < 0 {
             return false;
@@ -74,159 +60,975 @@ pub mod state_functions {
         true
     }
 
+    /// Appends a message of the server to the audit log of the user
     pub fn custom_log_line(
         user: &User,
         message: String,
-        logpath: &str,
-    ) -> Result<(), std::io::Error> {
-        let time = DateTime::<Utc>::from_utc(Local::now().naive_utc(), Utc).timestamp() + 1;
-        let mut ot = HashMap::new();
-        ot.insert("TYPE".to_string(), vec!["SYSTEM MESSAGE".to_string()]);
-        ot.insert("MESSAGE".to_string(), vec![message]);
-        let pl = RequestPayload {
-            T: Some(time.try_into().expect("Time went backwards")),
-            Tp: None,
-            Td: None,
-            L: None,
-            O: Some(ot),
-        };
-        pl.log_audit(user, logpath)?;
+        db: &DatabaseState,
+    ) -> Result<(), rusqlite::Error> {
+        let time = Utc::now()
+            .timestamp()
+            .try_into()
+            .expect("Time went backwards");
+        db.append_audit(&user.id, AuditRecord::system(message, time))
+    }
+
+    /// Sends the given verification code to its email address, rendered from the given
+    /// template and containing a link to the given endpoint (`{endpoint}/{email}/{code}`)
+    fn send_code(
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        verification: &Verification,
+        template: &str,
+        endpoint: &str,
+        subject: &str,
+    ) -> Result<(), smtp_handler::MailError> {
+        let mut values = HashMap::new();
+        values.insert("email", verification.email.clone());
+        values.insert("code", verification.code.to_string());
+        values.insert(
+            "link",
+            format!(
+                "{}{}/{}/{}",
+                cfg.server_config.public_url.trim_end_matches('/'),
+                endpoint,
+                verification.email,
+                verification.code
+            ),
+        );
+        values.insert(
+            "expires",
+            smtp_handler::format_timestamp(verification.expires),
+        );
+        let body = smtp_handler::render_template_file(template, &values)?;
+
+        mailer.send(&verification.email, false, subject, body)
+    }
+    /// Sends the given verification code to its email address, containing a link
+    /// to the verification endpoint.
+    pub fn send_verification_code(
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        verification: &Verification,
+    ) -> Result<(), smtp_handler::MailError> {
+        send_code(
+            mailer,
+            cfg,
+            verification,
+            &cfg.file_locations.verification_body_scheme,
+            "/api/verify",
+            "Dead Man Notification Beacon: Verify your Email",
+        )
+    }
+    /// Sends the given revival code to its email address, containing a link
+    /// to the revival endpoint.
+    pub fn send_revival_code(
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        verification: &Verification,
+    ) -> Result<(), smtp_handler::MailError> {
+        send_code(
+            mailer,
+            cfg,
+            verification,
+            &cfg.file_locations.revival_body_scheme,
+            "/api/revive",
+            "Dead Man Notification Beacon: Revive your account",
+        )
+    }
+    /// Sends the given code to the new email of a user, containing a link
+    /// to the email confirmation endpoint.
+    fn send_email_change_code(
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        verification: &Verification,
+    ) -> Result<(), smtp_handler::MailError> {
+        send_code(
+            mailer,
+            cfg,
+            verification,
+            &cfg.file_locations.email_change_body_scheme,
+            "/api/email",
+            "Dead Man Notification Beacon: Verify your new Email",
+        )
+    }
+
+    /// Returns the beacon a heartbeat came from: the `beacon` in `O`, otherwise the name of
+    /// the device which sent it, otherwise the default beacon
+    fn beacon_of(payload: &RequestPayload, device: Option<&Device>) -> String {
+        match (payload.get_extra("beacon"), device) {
+            (Some(beacon), _) if !beacon.trim().is_empty() => beacon.trim().to_string(),
+            (_, Some(device)) => device.name.clone(),
+            _ => DEFAULT_BEACON.to_string(),
+        }
+    }
+    /// Stores the deadline of the beacon the heartbeat came from and sends the resulting
+    /// deadline of the user, according to its beacon policy, to the thread in main.rs
+    fn set_beacon_deadline(
+        user: &User,
+        device: Option<&Device>,
+        payload: &RequestPayload,
+        deadline_db: &DatabaseState,
+        tx: &Sender<(String, u32)>,
+        expires: u32,
+    ) -> Result<(), String> {
+        deadline_db
+            .set_deadline(&user.id, &beacon_of(payload, device), expires)
+            .map_err(|err| err.to_string())?;
+        send_user_deadline(user, deadline_db, tx)
+    }
+    /// Sends the current deadline of the user to the thread in main.rs, if it has one
+    fn send_user_deadline(
+        user: &User,
+        deadline_db: &DatabaseState,
+        tx: &Sender<(String, u32)>,
+    ) -> Result<(), String> {
+        if let Some(deadline) = deadline_db
+            .get_deadline(&user.id)
+            .map_err(|err| err.to_string())?
+        {
+            tx.send((user.id.clone(), deadline))
+                .map_err(|err| err.to_string())?;
+        }
         Ok(())
     }
 
-    pub fn test() -> HttpResponse {
-        HttpResponse::Ok().json(ResponsePayload::new_static_message(200, "Auth Successful"))
+    pub fn test() -> ResponsePayload {
+        ResponsePayload::new_static_message(200, "Auth Successful")
     }
     pub fn audit(
         user: User,
+        device: Option<&Device>,
+        deadline_db: DatabaseState,
         tx: Sender<(String, u32)>,
         payload: web::Json<RequestPayload>,
-        logpath: &str,
-    ) -> HttpResponse {
+        default_interval: Option<u32>,
+    ) -> ResponsePayload {
         if !is_positive(&payload.T) {
-            return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                400,
-                "Timestamp can't be from the future",
-            ));
+            return ResponsePayload::error(ErrorCode::TimestampInFuture);
         }
 
-        if user.state >= 10 {
-            return HttpResponse::Conflict().json(ResponsePayload::new_static_message(
-                409,
-                "You are marked as deceased",
-            ));
+        if user.state.is_deceased() {
+            return ResponsePayload::error(ErrorCode::Deceased);
         }
 
-        let timestamp: u32 = match payload.Td {
+        let timestamp: u32 = match payload.Td.or(default_interval) {
             Some(time) => {
                 u32::try_from(Utc::now().timestamp()).expect("Time went backwards") + time
             }
             None => 0,
         };
-        if let Err(err) = tx.send((user.id.clone(), timestamp)) {
+        if let Err(err) = set_beacon_deadline(&user, device, &payload, &deadline_db, &tx, timestamp)
+        {
             log::error!("{}", err);
-            return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+            return ResponsePayload::status_500();
         }
 
-        if let Err(err) = payload.log_audit(&user, logpath) {
+        if let Err(err) = payload.log_audit(AuditKind::Audit, &user, device, &deadline_db) {
             log::error!("{}", err);
-            return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+            return ResponsePayload::status_500();
         };
 
-        HttpResponse::Ok().json(ResponsePayload::status_200())
+        ResponsePayload::status_200()
     }
     pub fn sign(
         user: User,
+        device: Option<&Device>,
         db: DatabaseState,
+        alarm_db: DatabaseState,
         payload: web::Json<RequestPayload>,
-        logpath: &str,
-    ) -> HttpResponse {
+    ) -> ResponsePayload {
         if !is_positive(&payload.T) {
-            return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                400,
-                "Timestamp can't be from the future",
-            ));
+            return ResponsePayload::error(ErrorCode::TimestampInFuture);
         }
 
-        if !match db.update_state_user(&user.id, 10) {
-            Ok(val) => val,
-            Err(err) => {
-                log::error!("{}", err);
-                return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+        match db.update_state_user(&user.id, UserState::Deceased, TransitionCause::Sign) {
+            Ok(Ok(_)) => (),
+            Ok(Err(err)) => {
+                return ResponsePayload::error_with_message(err.code(), err.to_string())
             }
-        } {
-            return HttpResponse::Conflict().json(ResponsePayload::new_static_message(
-                409,
-                "You are marked as deceased",
-            ));
-        };
-
-        match payload.log_audit(&user, logpath) {
             Err(err) => {
                 log::error!("{}", err);
-                return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+                return ResponsePayload::status_500();
             }
-            _ => (),
+        }
+        // An alarm which is over belongs to an earlier incident the user survived,
+        // it would keep the alarm engine from raising a new one
+        let finished = alarm_db
+            .get_alarm_by_id(&user.id)
+            .and_then(|alarm| match alarm {
+                Some(alarm) if alarm.acknowledged.is_some() || alarm.admin_notified.is_some() => {
+                    alarm_db.delete_user(&user.id)
+                }
+                _ => Ok(()),
+            });
+        if let Err(err) = finished {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+
+        if let Err(err) = payload.log_audit(AuditKind::Sign, &user, device, &db) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
         };
 
-        db.kill().expect("Failed to close database!");
-        HttpResponse::Ok().json(ResponsePayload::status_200())
+        if let Err(err) = db.kill() {
+            log::error!("Failed to close database!\n: {}", err);
+        }
+        ResponsePayload::status_200()
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn ilive(
         user: User,
+        device: Option<&Device>,
         db: DatabaseState,
+        deadline_db: DatabaseState,
         tx: Sender<(String, u32)>,
         payload: web::Json<RequestPayload>,
-        logpath: &str,
-    ) -> HttpResponse {
+        default_interval: Option<u32>,
+    ) -> ResponsePayload {
         if !is_positive(&payload.T) {
-            return HttpResponse::BadRequest().json(ResponsePayload::new_static_message(
-                400,
-                "Timestamp can't be from the future",
-            ));
+            return ResponsePayload::error(ErrorCode::TimestampInFuture);
         }
 
-        let timestamp: u32 = match payload.Td {
+        let timestamp: u32 = match payload.Td.or(default_interval) {
             Some(time) => {
                 u32::try_from(Utc::now().timestamp()).expect("Time went backwards") + time
             }
             None => 0,
         };
         // Update the state of the user
-        if !match db.update_state_user(&user.id, 0) {
-            Ok(val) => val,
+        match db.update_state_user(&user.id, UserState::Active, TransitionCause::Ilive) {
+            Ok(Ok(_)) => (),
+            Ok(Err(err)) => {
+                return ResponsePayload::error_with_message(err.code(), err.to_string())
+            }
             Err(err) => {
                 log::error!("{}", err);
-                return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+                return ResponsePayload::status_500();
             }
-        } {
-            return HttpResponse::Conflict().json(ResponsePayload::new_static_message(
-                409,
-                "You are marked as deceased",
-            ));
-        };
-        // Send the expected time to the thread in main.rs to collect outtimed users
-        if let Err(err) = tx.send((user.id.clone(), timestamp)) {
+        }
+        // Store the expected time of the beacon and send the resulting deadline of the user
+        // to the thread in main.rs to collect outtimed users
+        if let Err(err) = set_beacon_deadline(&user, device, &payload, &deadline_db, &tx, timestamp)
+        {
             log::error!("{}", err);
-            return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+            return ResponsePayload::status_500();
         }
         // Log this
-        if let Err(err) = payload.log_audit(&user, logpath) {
+        if let Err(err) = payload.log_audit(AuditKind::Heartbeat, &user, device, &db) {
             log::error!("{}", err);
-            return HttpResponse::InternalServerError().json(ResponsePayload::status_500());
+            return ResponsePayload::status_500();
         };
 
-        db.kill().expect("Failed to close database!");
-        HttpResponse::Ok().json(ResponsePayload::status_200())
+        if let Err(err) = db.kill() {
+            log::error!("Failed to close database!\n: {}", err);
+        }
+        ResponsePayload::status_200()
     }
 
-    pub fn stat(user: User, init_time: u32) -> HttpResponse {
+    pub fn stat(user: User, init_time: u32) -> ResponsePayload {
         let now: u32 = chrono::offset::Utc::now()
             .timestamp()
             .try_into()
             .expect("Time went backwards");
         let diff = now - init_time;
         let r = ServerStatus::new("".to_string(), user.email, diff, -1);
-        HttpResponse::Ok().json(ResponsePayload::new(200, ResponsePayloadTypes::Status(r)))
+        ResponsePayload::new(200, ResponsePayloadTypes::Status(r))
+    }
+    /// Returns the entries of the audit log of the user matching the query, newest first
+    pub fn audit_entries(
+        user: User,
+        query: &AuditQuery,
+        audit_db: DatabaseState,
+    ) -> ResponsePayload {
+        match audit_db.query_audit(&user.id, query) {
+            Ok(entries) => ResponsePayload::new(200, ResponsePayloadTypes::Audit(entries)),
+            Err(err) => {
+                log::error!("{}", err);
+                ResponsePayload::status_500()
+            }
+        }
+    }
+    /// Verifies the hash chain of the audit log of the user, see `audit_log::verify`
+    pub fn verify_audit_log(
+        user: User,
+        key: &ServerKey,
+        audit_db: DatabaseState,
+    ) -> ResponsePayload {
+        match audit_db.get_audit_lines(&user.id) {
+            Ok(lines) => ResponsePayload::new(
+                200,
+                ResponsePayloadTypes::AuditVerification(audit_log::verify(&user.id, key, lines)),
+            ),
+            Err(err) => {
+                log::error!("{}", err);
+                ResponsePayload::status_500()
+            }
+        }
+    }
+    /// Returns every state change of the user, oldest first
+    pub fn history(user: User, pool: &DbPool) -> ResponsePayload {
+        let history: Result<Vec<StateChange>, rusqlite::Error> =
+            DatabaseState::from_pool(pool, "state_history")
+                .and_then(|history_db| history_db.get_state_history(&user.id));
+        match history {
+            Ok(history) => ResponsePayload::new(200, ResponsePayloadTypes::History(history)),
+            Err(err) => {
+                log::error!("{}", err);
+                ResponsePayload::status_500()
+            }
+        }
+    }
+
+    /// Settings B: Change the default check-in interval (given in `Td`)
+    pub fn change_interval(
+        user: User,
+        settings_db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        let interval = match payload.Td {
+            Some(val) if val > 0 => val,
+            _ => return ResponsePayload::error(ErrorCode::InvalidInterval),
+        };
+        let mut settings = match settings_db.get_settings_by_id(&user.id) {
+            Ok(val) => val.unwrap_or_else(|| UserSettings::default_for(&user.id)),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        settings.interval = Some(interval);
+        if let Err(err) = settings_db.set_settings(&settings) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+
+        ResponsePayload::new(200, ResponsePayloadTypes::Settings(settings))
+    }
+    /// Settings C: Change the notification email (given as `email` in `O`). The new email is
+    /// sent a verification code and only used once it was confirmed, see `confirm_email`.
+    pub fn change_email(
+        user: User,
+        db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+        pool: &DbPool,
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+    ) -> ResponsePayload {
+        let email = match payload.get_extra("email") {
+            Some(val) if val.parse::<lettre::Address>().is_ok() => val.clone(),
+            _ => return ResponsePayload::error(ErrorCode::InvalidEmail),
+        };
+        match db.get_user_by_email(&email) {
+            Ok(None) => (),
+            Ok(Some(_)) => return ResponsePayload::error(ErrorCode::EmailAlreadyRegistered),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        let veri_db = match DatabaseState::from_pool(pool, "email_verification") {
+            Ok(val) => val,
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        let code = match veri_db.generate_email_change_code(&user.id, email.clone()) {
+            Ok(Some(code)) => code,
+            Ok(None) => return ResponsePayload::error(ErrorCode::EmailAlreadySubmitted),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        if let Err(err) = send_email_change_code(mailer, cfg, &code) {
+            log::error!("Failed to send email change code!\n: {}", err);
+            // Remove the entry again, so the email can be submitted once more
+            if let Err(err) = veri_db.delete_verification_by_email(&code.email) {
+                log::error!("{}", err);
+            }
+            return ResponsePayload::status_500();
+        }
+        if let Err(err) = custom_log_line(
+            &user,
+            format!("Change of the notification email to {} requested", email),
+            &db,
+        ) {
+            log::error!("{}", err);
+        }
+
+        ResponsePayload::new_static_message(200, "Awaiting verification")
+    }
+    /// Changes the notification email of the user to the given one, which was just confirmed
+    /// with the code sent by `change_email`
+    pub fn confirm_email(mut user: User, db: DatabaseState, email: String) -> ResponsePayload {
+        match db.get_user_by_email(&email) {
+            Ok(None) => (),
+            Ok(Some(_)) => return ResponsePayload::error(ErrorCode::EmailAlreadyRegistered),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        if let Err(err) = db.update_email_user(&user.id, &email) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+        if let Err(err) = custom_log_line(
+            &user,
+            format!(
+                "Notification email changed from {} to {}",
+                user.email, email
+            ),
+            &db,
+        ) {
+            log::error!("{}", err);
+        }
+
+        user.email = email;
+        ResponsePayload::new(200, ResponsePayloadTypes::User(user))
+    }
+    /// Settings D, Token A: Rotate the API token of the user. The old token stays valid for the
+    /// given number of seconds, so every beacon can be switched over. The user id and
+    /// everything keyed by it stay the same.
+    pub fn rotate_token(user: User, db: DatabaseState, overlap: u32) -> ResponsePayload {
+        let token = DatabaseState::generate_token();
+        let overlap_until =
+            u32::try_from(Utc::now().timestamp()).expect("Time went backwards") + overlap;
+        if let Err(err) = db.rotate_user_token(&user.id, &token, overlap_until) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+        if let Err(err) = custom_log_line(
+            &user,
+            format!(
+                "Token was rotated, the old token stays valid for {} seconds",
+                overlap
+            ),
+            &db,
+        ) {
+            log::error!("{}", err);
+        }
+        ResponsePayload::new(
+            200,
+            ResponsePayloadTypes::Credentials(Credentials { user, token }),
+        )
+    }
+    /// Token B: Revoke every token of the user immediately. A new token is issued after the user
+    /// verified their email again via the register endpoint.
+    pub fn revoke_token(user: User, db: DatabaseState) -> ResponsePayload {
+        if let Err(err) = db.revoke_user_tokens(&user.id) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+        if let Err(err) = custom_log_line(&user, "Token was revoked".to_string(), &db) {
+            log::error!("{}", err);
+        }
+        ResponsePayload::new_static_message(200, "Token revoked")
+    }
+    /// Settings E: Pause or resume monitoring (`paused` in `O`, either "true" or "false").
+    /// While paused a missed deadline does not mark the user as deceased,
+    /// the next heartbeat via ilive resumes monitoring.
+    pub fn pause(
+        mut user: User,
+        db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        let (new_state, message) = match payload.get_extra("paused").map(|v| v.as_str()) {
+            Some("true") => (UserState::Paused, "Monitoring was paused"),
+            Some("false") => (UserState::Active, "Monitoring was resumed"),
+            _ => return ResponsePayload::error(ErrorCode::InvalidPause),
+        };
+        match db.update_state_user(&user.id, new_state, TransitionCause::Pause) {
+            Ok(Ok(_)) => (),
+            Ok(Err(err)) => {
+                return ResponsePayload::error_with_message(err.code(), err.to_string())
+            }
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        if let Err(err) = custom_log_line(&user, message.to_string(), &db) {
+            log::error!("{}", err);
+        }
+
+        user.state = new_state;
+        ResponsePayload::new(200, ResponsePayloadTypes::User(user))
+    }
+    /// Settings F: Delete the account including all of its data.
+    /// The account email has to be given as `confirm` in `O` to prevent accidental deletion.
+    pub fn delete_account(
+        user: User,
+        db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        if payload.get_extra("confirm") != Some(&user.email) {
+            return ResponsePayload::error(ErrorCode::DeletionNotConfirmed);
+        }
+        if let Err(err) = db.delete_account(&user.id, &user.email) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+
+        log::debug!("USER {} deleted their account", user.id);
+        ResponsePayload::new_static_message(200, "Account deleted")
+    }
+
+    /// Settings G: Change the beacon policy (`policy` in `O`, either "any" or "all")
+    pub fn change_policy(
+        user: User,
+        settings_db: DatabaseState,
+        deadline_db: DatabaseState,
+        tx: Sender<(String, u32)>,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        let policy = match payload
+            .get_extra("policy")
+            .and_then(|v| BeaconPolicy::from_name(v))
+        {
+            Some(val) => val,
+            None => return ResponsePayload::error(ErrorCode::InvalidPolicy),
+        };
+        let mut settings = match settings_db.get_settings_by_id(&user.id) {
+            Ok(val) => val.unwrap_or_else(|| UserSettings::default_for(&user.id)),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        settings.policy = policy;
+        if let Err(err) = settings_db.set_settings(&settings) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+        // The deadline of the user changes with the policy
+        if let Err(err) = send_user_deadline(&user, &deadline_db, &tx) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+
+        ResponsePayload::new(200, ResponsePayloadTypes::Settings(settings))
+    }
+    /// Settings H: Forget a beacon which is no longer used (`beacon` in `O`),
+    /// so its deadline doesn't count anymore
+    pub fn forget_beacon(
+        user: User,
+        deadline_db: DatabaseState,
+        tx: Sender<(String, u32)>,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        let beacon = match payload.get_extra("beacon") {
+            Some(beacon) => beacon.trim(),
+            None => return ResponsePayload::error(ErrorCode::MissingBeacon),
+        };
+        match deadline_db.delete_beacon_deadline(&user.id, beacon) {
+            Ok(true) => (),
+            Ok(false) => return ResponsePayload::error(ErrorCode::BeaconNotFound),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        if let Err(err) = send_user_deadline(&user, &deadline_db, &tx) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+        if let Err(err) = custom_log_line(
+            &user,
+            format!("Beacon {} was forgotten", beacon),
+            &deadline_db,
+        ) {
+            log::error!("{}", err);
+        }
+
+        ResponsePayload::new_static_message(200, "Beacon forgotten")
+    }
+
+    /// Settings I: Change how long the audit log of the user is kept (`entries` and/or
+    /// `max_age` in seconds in `O`). Either may be "default" to use the retention of the
+    /// server again, neither may exceed the retention of the server.
+    pub fn change_retention(
+        user: User,
+        settings_db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+        cfg: &ConfigAudit,
+    ) -> ResponsePayload {
+        let entries = payload.get_extra("entries");
+        let max_age = payload.get_extra("max_age");
+        if entries.is_none() && max_age.is_none() {
+            return ResponsePayload::error(ErrorCode::InvalidRetention);
+        }
+        let mut settings = match settings_db.get_settings_by_id(&user.id) {
+            Ok(val) => val.unwrap_or_else(|| UserSettings::default_for(&user.id)),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        if let Some(entries) = entries {
+            settings.audit_entries = match parse_retention(entries, Some(cfg.max_entries)) {
+                Some(val) => val,
+                None => return ResponsePayload::error(ErrorCode::InvalidRetention),
+            };
+        }
+        if let Some(max_age) = max_age {
+            settings.audit_max_age = match parse_retention(max_age, cfg.max_age) {
+                Some(val) => val,
+                None => return ResponsePayload::error(ErrorCode::InvalidRetention),
+            };
+        }
+        if let Err(err) = settings_db.set_settings(&settings) {
+            log::error!("{}", err);
+            return ResponsePayload::status_500();
+        }
+
+        ResponsePayload::new(200, ResponsePayloadTypes::Settings(settings))
+    }
+    /// Parses a retention of the settings API up to the given limit, returns None if it's
+    /// invalid. "default" is parsed as no retention, so the retention of the server applies.
+    fn parse_retention(value: &str, limit: Option<u32>) -> Option<Option<u32>> {
+        if value == "default" {
+            return Some(None);
+        }
+        match value.parse::<u32>() {
+            Ok(val) if val > 0 && limit.is_none_or(|limit| val <= limit) => Some(Some(val)),
+            _ => None,
+        }
+    }
+
+    /// Returns the signed link a contact can use to unsubscribe at any time
+    pub fn unsubscribe_link(cfg: &ConfigMain, key: &ServerKey, contact: &Contact) -> String {
+        format!(
+            "{}/api/contacts/unsubscribe/{}/{}",
+            cfg.server_config.public_url.trim_end_matches('/'),
+            contact.id,
+            key.sign(&format!("unsubscribe:{}:{}", contact.id, contact.email))
+        )
+    }
+    /// Verifies the signature of an unsubscribe link of the given contact
+    pub fn verify_unsubscribe(key: &ServerKey, contact: &Contact, signature: &str) -> bool {
+        key.verify(
+            &format!("unsubscribe:{}:{}", contact.id, contact.email),
+            signature,
+        )
+    }
+
+    /// Sends the given contact a link to confirm it wants to receive notifications about the user
+    fn send_contact_confirmation(
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        key: &ServerKey,
+        user: &User,
+        contact: &Contact,
+        verification: &Verification,
+    ) -> Result<(), smtp_handler::MailError> {
+        let mut values = HashMap::new();
+        values.insert("name", contact.name.clone());
+        values.insert("email", user.email.clone());
+        values.insert(
+            "link",
+            format!(
+                "{}/api/contacts/confirm/{}/{}",
+                cfg.server_config.public_url.trim_end_matches('/'),
+                contact.id,
+                verification.code
+            ),
+        );
+        values.insert("unsubscribe_link", unsubscribe_link(cfg, key, contact));
+        values.insert(
+            "expires",
+            smtp_handler::format_timestamp(verification.expires),
+        );
+        let body =
+            smtp_handler::render_template_file(&cfg.file_locations.contact_body_scheme, &values)?;
+
+        mailer.send(
+            &contact.email,
+            false,
+            "Dead Man Notification Beacon: You were added as a trusted contact",
+            body,
+        )
+    }
+
+    /// Contacts B: Add a trusted contact (`name`, `email` and `message` in `O`).
+    /// The contact is sent a confirmation link and only notified once it confirmed.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_contact(
+        user: User,
+        contact_db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+        pool: &DbPool,
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        key: &ServerKey,
+    ) -> ResponsePayload {
+        let (name, email, message) = match (
+            payload.get_extra("name"),
+            payload.get_extra("email"),
+            payload.get_extra("message"),
+        ) {
+            (Some(name), Some(email), message) if email.parse::<lettre::Address>().is_ok() => (
+                name.clone(),
+                email.clone(),
+                message.cloned().unwrap_or_default(),
+            ),
+            _ => return ResponsePayload::error(ErrorCode::InvalidContact),
+        };
+        match contact_db.get_contacts_by_user(&user.id) {
+            Ok(contacts) if contacts.len() >= MAX_CONTACTS => {
+                return ResponsePayload::error(ErrorCode::TooManyContacts)
+            }
+            Ok(_) => (),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        let contact = match contact_db.add_contact(&user.id, &name, &email, &message) {
+            Ok(val) => val,
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        let veri_db = match DatabaseState::from_pool(pool, "contact_verification") {
+            Ok(val) => val,
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        let sent = match veri_db.generate_verification_code_with_lifetime(
+            email.clone(),
+            false,
+            CONTACT_CONFIRMATION_LIFETIME,
+        ) {
+            Ok(Some(verification)) => {
+                send_contact_confirmation(mailer, cfg, key, &user, &contact, &verification)
+                    .map_err(|err| err.to_string())
+            }
+            Ok(None) => Err("No verification code generated".to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = sent {
+            log::error!("Failed to send contact confirmation!\n: {}", err);
+            // Remove the contact again, so it can be added once more. The code
+            // points to a non-existing contact from now on and just expires.
+            if let Err(err) = contact_db.delete_contact(&user.id, contact.id) {
+                log::error!("{}", err);
+            }
+            return ResponsePayload::status_500();
+        }
+        if let Err(err) = custom_log_line(
+            &user,
+            format!("Contact {} was added and awaits confirmation", email),
+            &contact_db,
+        ) {
+            log::error!("{}", err);
+        }
+
+        ResponsePayload::new(200, ResponsePayloadTypes::Contact(contact))
+    }
+    /// Contacts C: Remove a trusted contact (`id` in `O`)
+    pub fn remove_contact(
+        user: User,
+        contact_db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        let id: u32 = match payload.get_extra("id").and_then(|v| v.parse().ok()) {
+            Some(val) => val,
+            None => return ResponsePayload::error(ErrorCode::InvalidContactId),
+        };
+        match contact_db.delete_contact(&user.id, id) {
+            Ok(true) => (),
+            Ok(false) => return ResponsePayload::error(ErrorCode::ContactNotFound),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        if let Err(err) = custom_log_line(&user, format!("Contact {} was removed", id), &contact_db)
+        {
+            log::error!("{}", err);
+        }
+
+        ResponsePayload::new_static_message(200, "Contact removed")
+    }
+
+    /// Devices B: Register a new device with its own token (`name` in `O`)
+    pub fn add_device(
+        user: User,
+        device_db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        let name = match payload.get_extra("name") {
+            Some(name) if !name.trim().is_empty() => name.trim().to_string(),
+            _ => return ResponsePayload::error(ErrorCode::InvalidDeviceName),
+        };
+        match device_db.get_devices_by_user(&user.id) {
+            Ok(devices) if devices.len() >= MAX_DEVICES => {
+                return ResponsePayload::error(ErrorCode::TooManyDevices)
+            }
+            Ok(_) => (),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        let (device, token) = match device_db.add_device(&user.id, &name) {
+            Ok(val) => val,
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        };
+        if let Err(err) = custom_log_line(
+            &user,
+            format!("Device {} ({}) was added", device.name, device.id),
+            &device_db,
+        ) {
+            log::error!("{}", err);
+        }
+
+        ResponsePayload::new(
+            200,
+            ResponsePayloadTypes::Device(DeviceCredentials { device, token }),
+        )
+    }
+    /// Devices C: Revoke a device (`id` in `O`), its token becomes invalid immediately
+    pub fn remove_device(
+        user: User,
+        device_db: DatabaseState,
+        payload: web::Json<RequestPayload>,
+    ) -> ResponsePayload {
+        let id: u32 = match payload.get_extra("id").and_then(|v| v.parse().ok()) {
+            Some(val) => val,
+            None => return ResponsePayload::error(ErrorCode::InvalidDeviceId),
+        };
+        match device_db.delete_device(&user.id, id) {
+            Ok(true) => (),
+            Ok(false) => return ResponsePayload::error(ErrorCode::DeviceNotFound),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        if let Err(err) = custom_log_line(&user, format!("Device {} was revoked", id), &device_db) {
+            log::error!("{}", err);
+        }
+
+        ResponsePayload::new_static_message(200, "Device revoked")
+    }
+
+    /// Revive a user who was wrongly marked as deceased, after the user proved its identity.
+    /// The user is active again (the revival is recorded in the state history), a running alarm
+    /// is acknowledged and contacts which were already notified are told it was a false alarm.
+    pub fn revive(
+        mut user: User,
+        db: DatabaseState,
+        pool: &DbPool,
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        key: &ServerKey,
+    ) -> ResponsePayload {
+        match db.update_state_user(&user.id, UserState::Active, TransitionCause::Revival) {
+            Ok(Ok(_)) => (),
+            Ok(Err(_)) => return ResponsePayload::error(ErrorCode::NotDeceased),
+            Err(err) => {
+                log::error!("{}", err);
+                return ResponsePayload::status_500();
+            }
+        }
+        user.state = UserState::Active;
+        if let Err(err) = custom_log_line(
+            &user,
+            "User was revived after proving their identity via email".to_string(),
+            &db,
+        ) {
+            log::error!("{}", err);
+        }
+
+        let alarm = DatabaseState::from_pool(pool, "alarms").and_then(|alarm_db| {
+            let alarm = alarm_db.get_alarm_by_id(&user.id)?;
+            if let Some(mut alarm) = alarm.clone() {
+                if alarm.acknowledged.is_none() {
+                    alarm.acknowledged =
+                        Some(u32::try_from(Utc::now().timestamp()).expect("Time went backwards"));
+                    alarm_db.set_alarm(&alarm)?;
+                }
+            }
+            Ok(alarm)
+        });
+        let alarm = match alarm {
+            Ok(val) => val,
+            Err(err) => {
+                log::error!("{}", err);
+                None
+            }
+        };
+        // Only contacts which received a notification have to be told it was a false alarm
+        if alarm.is_some_and(|alarm| alarm.contacts_notified.is_some()) {
+            let contacts = DatabaseState::from_pool(pool, "contacts")
+                .and_then(|contact_db| contact_db.get_contacts_by_user(&user.id));
+            match contacts {
+                Ok(contacts) => {
+                    let contacts: Vec<Contact> =
+                        contacts.into_iter().filter(|c| c.active).collect();
+                    let notified = notify_false_alarm(mailer, cfg, key, &user, &contacts);
+                    if let Err(err) = custom_log_line(
+                        &user,
+                        format!(
+                            "Notification about the false alarm was sent to {} of {} contacts",
+                            notified,
+                            contacts.len()
+                        ),
+                        &db,
+                    ) {
+                        log::error!("{}", err);
+                    }
+                }
+                Err(err) => log::error!("{}", err),
+            }
+        }
+
+        log::debug!("USER {} was revived", user.id);
+        ResponsePayload::new(200, ResponsePayloadTypes::User(user))
+    }
+    /// Tells the given contacts that the user is alive, returns the number of contacts
+    /// which were notified successfully
+    fn notify_false_alarm(
+        mailer: &Mailer,
+        cfg: &ConfigMain,
+        key: &ServerKey,
+        user: &User,
+        contacts: &[Contact],
+    ) -> usize {
+        let template = match std::fs::read_to_string(&cfg.file_locations.false_alarm_body_scheme) {
+            Ok(val) => val,
+            Err(err) => {
+                log::error!("Failed to read email template!\n: {}", err);
+                return 0;
+            }
+        };
+        let mut notified = 0;
+        for contact in contacts {
+            let mut values = HashMap::new();
+            values.insert("name", contact.name.clone());
+            values.insert("email", user.email.clone());
+            values.insert("unsubscribe_link", unsubscribe_link(cfg, key, contact));
+            match mailer.send(
+                &contact.email,
+                false,
+                "Dead Man Notification Beacon: False alarm",
+                smtp_handler::render_template(&template, &values),
+            ) {
+                Ok(_) => notified += 1,
+                Err(err) => log::error!("Failed to notify contact {}!\n: {}", contact.id, err),
+            }
+        }
+        notified
     }
 }
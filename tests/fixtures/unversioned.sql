@@ -0,0 +1,19 @@
+-- A database as created by the server before schema migrations were introduced
+CREATE TABLE users ('id' TEXT, 'email' TEXT, 'state' INTEGER, PRIMARY KEY('id'));
+CREATE TABLE state_history ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'old_state' INTEGER, 'new_state' INTEGER, 'cause' TEXT, 'timestamp' INTEGER);
+CREATE TABLE verification ('email' TEXT, 'code' INTEGER, 'expires' INTEGER);
+CREATE TABLE contact_verification ('email' TEXT, 'code' INTEGER, 'expires' INTEGER);
+CREATE TABLE revival_verification ('email' TEXT, 'code' INTEGER, 'expires' INTEGER);
+CREATE TABLE deadlines ('id' TEXT, 'expires' INTEGER, PRIMARY KEY('id'));
+CREATE TABLE settings ('id' TEXT, 'interval' INTEGER, PRIMARY KEY('id'));
+CREATE TABLE alarms ('id' TEXT, 'ack_code' TEXT, 'raised' INTEGER, 'grace_until' INTEGER, 'warned' INTEGER, 'contacts_notified' INTEGER, 'admin_notified' INTEGER, 'acknowledged' INTEGER, PRIMARY KEY('id'));
+CREATE TABLE contacts ('id' INTEGER PRIMARY KEY AUTOINCREMENT, 'user_id' TEXT, 'name' TEXT, 'email' TEXT, 'message' TEXT, 'active' INTEGER);
+
+INSERT INTO users VALUES ('alive00000000000000000000000000000000000000000000000000000000000', 'alive@example.com', 0);
+INSERT INTO users VALUES ('dead000000000000000000000000000000000000000000000000000000000000', 'dead@example.com', 15);
+INSERT INTO state_history (user_id, old_state, new_state, cause, timestamp) VALUES ('alive00000000000000000000000000000000000000000000000000000000000', -1, 0, 'registration', 1600000000);
+INSERT INTO verification VALUES ('new@example.com', 123456789012345678, 1600000600);
+INSERT INTO deadlines VALUES ('alive00000000000000000000000000000000000000000000000000000000000', 1600086400);
+INSERT INTO settings VALUES ('alive00000000000000000000000000000000000000000000000000000000000', 86400);
+INSERT INTO alarms VALUES ('dead000000000000000000000000000000000000000000000000000000000000', 'ackcode', 1600000000, 1600003600, 1600000001, 1600003600, NULL, NULL);
+INSERT INTO contacts (user_id, name, email, message, active) VALUES ('dead000000000000000000000000000000000000000000000000000000000000', 'Bob', 'bob@example.com', 'Bye', 1);